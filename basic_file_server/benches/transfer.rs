@@ -0,0 +1,134 @@
+//! GET throughput with and without the sendfile fast path.
+//!
+//!     cargo bench --bench transfer
+//!
+//! BFS_BENCH_MB sets the file size (default 256) and BFS_BENCH_RUNS the
+//! number of timed GETs per mode (default 5). No log subscriber is
+//! installed, so the results table on stderr is the only output.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use basic_file_server::Server;
+
+const FILE_NAME: &str = "bench.bin";
+
+struct Mode {
+    label: &'static str,
+    zero_copy: bool,
+    /// Touch the file before every run so no cached digest can be used.
+    invalidate_cache: bool,
+    port: u16,
+}
+
+fn main() {
+    let size_mb = env_or("BFS_BENCH_MB", 256);
+    let runs = env_or("BFS_BENCH_RUNS", 5);
+
+    let dir = std::env::temp_dir().join(format!("bfs-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create bench dir");
+    let path = dir.join(FILE_NAME);
+    write_file(&path, size_mb * 1024 * 1024);
+
+    let modes = [
+        Mode { label: "copy (write_buf)", zero_copy: false, invalidate_cache: true, port: 47311 },
+        Mode { label: "sendfile, hashed", zero_copy: true, invalidate_cache: true, port: 47312 },
+        Mode { label: "sendfile, cached digest", zero_copy: true, invalidate_cache: false, port: 47313 },
+    ];
+    let mut results = Vec::new();
+    for mode in &modes {
+        let addr = format!("127.0.0.1:{}", mode.port);
+        let mut server = Server::new(&addr, dir.clone()).with_zero_copy(mode.zero_copy);
+        std::thread::spawn(move || server.run());
+        let mut stream = connect(&addr);
+
+        // Warm the page cache (and, for the cached mode, the digest cache)
+        get(&mut stream);
+        let mut wall = Duration::ZERO;
+        let mut cpu = Duration::ZERO;
+        for _ in 0..runs {
+            if mode.invalidate_cache {
+                File::options().write(true).open(&path).unwrap()
+                    .set_modified(SystemTime::now()).unwrap();
+            }
+            let cpu_start = process_cpu();
+            let start = Instant::now();
+            get(&mut stream);
+            wall += start.elapsed();
+            cpu += process_cpu() - cpu_start;
+        }
+        results.push((mode.label, wall, cpu));
+    }
+
+    let total_mb = (size_mb * runs) as f64;
+    eprintln!();
+    eprintln!("{} MiB x {} GETs over loopback, client and server in one process", size_mb, runs);
+    eprintln!("{:<26} {:>10} {:>16}", "mode", "MiB/s", "CPU ms per GiB");
+    for (label, wall, cpu) in results {
+        eprintln!("{:<26} {:>10.0} {:>16.0}",
+            label,
+            total_mb / wall.as_secs_f64(),
+            cpu.as_secs_f64() * 1000.0 / (total_mb / 1024.0));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Incompressible-looking content from a xorshift generator.
+fn write_file(path: &Path, len: usize) {
+    let mut file = std::io::BufWriter::new(File::create(path).expect("create bench file"));
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    for _ in 0..len / 8 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        file.write_all(&state.to_le_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+}
+
+fn connect(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return stream;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server at {} did not come up", addr);
+}
+
+/// One plain GET, discarding the body.
+fn get(stream: &mut TcpStream) {
+    stream.write_all(format!("GET {}\n", FILE_NAME).as_bytes()).unwrap();
+    let mut reader = BufReader::with_capacity(256 * 1024, stream.try_clone().unwrap());
+    let mut header = String::new();
+    reader.read_line(&mut header).unwrap();
+    let size: u64 = header.trim_end().strip_prefix("FILE ")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| panic!("unexpected header: {}", header));
+    let copied = std::io::copy(&mut (&mut reader).take(size), &mut std::io::sink()).unwrap();
+    assert_eq!(copied, size);
+    let mut trailer = String::new();
+    reader.read_line(&mut trailer).unwrap(); // newline after the body
+    trailer.clear();
+    reader.read_line(&mut trailer).unwrap();
+    assert!(trailer.starts_with("MD5 "), "unexpected trailer: {}", trailer);
+}
+
+/// User plus system CPU time of the whole process so far.
+fn process_cpu() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills the struct it is given
+    let usage = unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+        usage.assume_init()
+    };
+    let tv = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    tv(usage.ru_utime) + tv(usage.ru_stime)
+}
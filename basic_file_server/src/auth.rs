@@ -0,0 +1,216 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{hmac, pbkdf2};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use crate::protocol::escape_name;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const SCHEME: &str = "pbkdf2-sha256";
+
+/// What a client presents in its `AUTH` command.
+#[derive(Clone)]
+pub enum Credentials {
+    /// `AUTH TOKEN <token>`
+    Token(String),
+    /// `AUTH USER <name> <password>`
+    Password { user: String, password: String },
+}
+
+impl Credentials {
+    /// The `AUTH ...` line to send, without the trailing newline. Fields are
+    /// escaped like file names so passwords may contain spaces.
+    pub fn command(&self) -> String {
+        match self {
+            Credentials::Token(token) => format!("AUTH TOKEN {}", escape_name(token)),
+            Credentials::Password { user, password } => format!("AUTH USER {} {}", escape_name(user), escape_name(password)),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print secrets
+        match self {
+            Credentials::Token(_) => f.write_str("Token(..)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?}, .. }}", user),
+        }
+    }
+}
+
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// Server-side credential store. With no tokens and no users configured,
+/// authentication is disabled and every connection is trusted.
+pub struct AuthConfig {
+    /// Pre-shared tokens, kept only as HMAC tags under a per-process key
+    /// so checks are constant time.
+    token_key: hmac::Key,
+    tokens: Vec<hmac::Tag>,
+    users: HashMap<String, PasswordHash>,
+    /// Checked against for names that are not in `users`, so an unknown
+    /// user takes as long to turn away as a wrong password.
+    unknown_user: PasswordHash,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        let mut key = [0u8; 32];
+        SystemRandom::new().fill(&mut key).expect("system RNG unavailable");
+        Self {
+            token_key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            tokens: Vec::new(),
+            users: HashMap::new(),
+            unknown_user: PasswordHash {
+                iterations: NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+                salt: vec![0; SALT_LEN],
+                hash: vec![0; HASH_LEN],
+            },
+        }
+    }
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+        .field("tokens", &self.tokens.len())
+        .field("users", &self.users.keys().collect::<Vec<_>>())
+        .finish()
+    }
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+
+    pub fn add_token(&mut self, token: &str) {
+        self.tokens.push(hmac::sign(&self.token_key, token.as_bytes()));
+    }
+
+    /// Load a users file: one `user:pbkdf2-sha256:<iterations>:<salt hex>:<hash hex>`
+    /// per line, as printed by `hash_password`. Blank lines and `#` comments are skipped.
+    pub fn load_users(&mut self, path: &Path) -> io::Result<()> {
+        let contents = std::fs::read_to_string(path)?;
+        self.add_users(contents.lines(), &format!("{:?}", path))
+    }
+
+    /// Add users-file entries given one per item; `source` names them in errors.
+    pub fn add_users<'a>(&mut self, lines: impl IntoIterator<Item = &'a str>, source: &str) -> io::Result<()> {
+        for (lineno, line) in lines.into_iter().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{} line {}: malformed entry", source, lineno + 1));
+            let [user, scheme, iterations, salt, hash] = line.split(':').collect::<Vec<_>>()[..] else {
+                return Err(invalid());
+            };
+            if scheme != SCHEME {
+                return Err(invalid());
+            }
+            let entry = PasswordHash {
+                iterations: iterations.parse().map_err(|_| invalid())?,
+                salt: decode_hex(salt).ok_or_else(invalid)?,
+                hash: decode_hex(hash).ok_or_else(invalid)?,
+            };
+            self.users.insert(user.to_string(), entry);
+        }
+        Ok(())
+    }
+
+    /// Check `credentials`. A password check derives a key, which takes a
+    /// while by design, for unknown users too.
+    pub fn verify(&self, credentials: &Credentials) -> bool {
+        match credentials {
+            Credentials::Token(token) => self.tokens.iter()
+                .any(|tag| hmac::verify(&self.token_key, token.as_bytes(), tag.as_ref()).is_ok()),
+            Credentials::Password { user, password } => {
+                let (entry, known) = match self.users.get(user) {
+                    Some(entry) => (entry, true),
+                    None => (&self.unknown_user, false),
+                };
+                let matches = pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, entry.iterations, &entry.salt, password.as_bytes(), &entry.hash).is_ok();
+                matches && known
+            }
+        }
+    }
+}
+
+/// Produce a users-file line for `user` with a fresh random salt.
+pub fn hash_password(user: &str, password: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).expect("system RNG unavailable");
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+    format!("{}:{}:{}:{}:{}", user, SCHEME, iterations, encode_hex(&salt), encode_hex(&hash))
+}
+
+/// Read a password from stdin, prompting on stderr with the terminal's
+/// echo off while it is typed. Input that is not a terminal is read as a
+/// plain line, so a password can be piped in.
+pub fn read_password(prompt: &str) -> io::Result<String> {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    if stdin.is_terminal() {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+        let echo_off = EchoOff::new()?;
+        let read = stdin.lock().read_line(&mut line);
+        drop(echo_off);
+        eprintln!();
+        read?;
+    } else {
+        stdin.lock().read_line(&mut line)?;
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Turns off echo on the terminal at stdin until dropped.
+struct EchoOff(libc::termios);
+
+impl EchoOff {
+    fn new() -> io::Result<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr before use
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(saved))
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings tcgetattr gave us
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
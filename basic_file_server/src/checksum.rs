@@ -0,0 +1,112 @@
+use sha2::Digest;
+use std::fmt;
+use std::str::FromStr;
+
+/// Streaming digest used for transfer integrity checks.
+pub trait Checksum: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// Hex digest of everything fed so far; the hasher stays usable.
+    fn hex_digest(&self) -> String;
+
+    fn algorithm(&self) -> Algorithm;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Algorithm {
+    #[default]
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 3] = [Algorithm::Md5, Algorithm::Sha256, Algorithm::Blake3];
+
+    /// Name used on the wire, e.g. in `HASH=sha256` and `HASH sha256 <hex>`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn hasher(self) -> Box<dyn Checksum> {
+        match self {
+            Algorithm::Md5 => Box::new(Md5(md5::Context::new())),
+            Algorithm::Sha256 => Box::new(Sha256(sha2::Sha256::new())),
+            Algorithm::Blake3 => Box::new(Blake3(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL.into_iter()
+            .find(|alg| alg.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unsupported hash algorithm: {}", s))
+    }
+}
+
+impl fmt::Debug for dyn Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum({})", self.algorithm())
+    }
+}
+
+struct Md5(md5::Context);
+
+impl Checksum for Md5 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.consume(data);
+    }
+
+    fn hex_digest(&self) -> String {
+        format!("{:x}", self.0.clone().finalize())
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Md5
+    }
+}
+
+struct Sha256(sha2::Sha256);
+
+impl Checksum for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn hex_digest(&self) -> String {
+        self.0.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Sha256
+    }
+}
+
+struct Blake3(blake3::Hasher);
+
+impl Checksum for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn hex_digest(&self) -> String {
+        self.0.finalize().to_hex().to_string()
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Blake3
+    }
+}
@@ -1,144 +1,1624 @@
 use async_std::io::{self, prelude::*, BufReader};
 use async_std::net::TcpStream;
-use clap::Parser;
-use md5::Context;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use futures_lite::future;
+use futures_rustls::TlsConnector;
+use rustls::pki_types::ServerName;
+use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{debug, info, trace, warn};
+
+use crate::auth::Credentials;
+use crate::checksum::{Algorithm, Checksum};
+use crate::compression::Compression;
+use crate::protocol::{escape_name, unescape_name, ArchiveFormat, Capabilities, Capability, EntryKind, Hello, PROTOCOL_VERSION};
+use crate::ratelimit::{Rate, TokenBucket};
+pub use crate::protocol::Entry;
+
+/// Smallest byte range worth its own connection in a segmented download.
+const MIN_SEGMENT: u64 = 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+/// A download that receives nothing for this long is treated as dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+/// Longest command line the server accepts.
+const MAX_COMMAND_LEN: usize = 8 * 1024;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Async non-blocking client with progress", long_about = None)]
 pub struct ClientCli {
-    /// server address, e.g. 127.0.0.1:4000
-    #[arg(short, long)]
-    pub addr: String,
+    #[command(subcommand)]
+    pub command: Option<ClientCommand>,
 
-    /// filename to GET; if omitted, client will list and prompt
+    /// server address, e.g. 127.0.0.1:4000 (required)
+    #[arg(short, long, global = true)]
+    pub addr: Option<String>,
+
+    /// filename to GET; if omitted, as are all other actions, start an interactive shell
     #[arg(short, long)]
     pub get: Option<String>,
 
+    /// local file to upload into the server's mount directory
+    #[arg(short, long, conflicts_with = "get")]
+    pub put: Option<PathBuf>,
+
+    /// names or globs (quoted, e.g. 'logs/*.log') to fetch in one MGET
+    #[arg(short, long, num_args = 1.., conflicts_with_all = ["get", "put"])]
+    pub mget: Vec<String>,
+
+    /// remote directory to fetch as an archive and unpack under --out
+    #[arg(short = 'd', long, conflicts_with_all = ["get", "put", "mget"])]
+    pub getdir: Option<String>,
+
+    /// archive format for --getdir: tar or tar.zst
+    #[arg(long, default_value_t = ArchiveFormat::Tar)]
+    pub format: ArchiveFormat,
+
     /// output directory
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub out: Option<PathBuf>,
+
+    /// list subdirectories recursively (LIST -r); without --get, only list
+    #[arg(short, long, conflicts_with_all = ["put", "mget", "getdir"])]
+    pub recursive: bool,
+
+    /// show size, mtime and type for each entry (LIST -l); without --get, only list
+    #[arg(short, long, conflicts_with_all = ["put", "mget", "getdir"])]
+    pub long: bool,
+
+    /// checksum algorithm to request: md5, sha256 or blake3 (default: plain MD5)
+    #[arg(long, global = true)]
+    pub hash: Option<Algorithm>,
+
+    /// compress GET transfers on the wire: zstd or gzip
+    #[arg(long)]
+    pub compress: Option<Compression>,
+
+    /// download no faster than this, e.g. 2MiB/s
+    #[arg(long, global = true)]
+    pub limit_rate: Option<Rate>,
+
+    /// fetch a single file over this many connections at once, each
+    /// downloading its own byte range
+    #[arg(long, default_value_t = 1, conflicts_with_all = ["put", "mget", "getdir"])]
+    pub connections: usize,
+
+    /// when a GET is cut off, reconnect and resume it up to this many times
+    #[arg(long, default_value_t = 0)]
+    pub retries: u32,
+
+    /// seconds to wait before the first retry, doubling for each one after
+    #[arg(long, default_value_t = 1)]
+    pub retry_backoff: u64,
+
+    /// connect over TLS, verifying against the bundled web PKI roots
+    #[arg(long, global = true)]
+    pub tls: bool,
+
+    /// PEM CA certificate to verify the server with (implies --tls)
+    #[arg(long, global = true)]
+    pub ca: Option<PathBuf>,
+
+    /// use TLS without verifying the server certificate (implies --tls)
+    #[arg(long, conflicts_with = "ca", global = true)]
+    pub insecure: bool,
+
+    /// user name for AUTH; the password comes from BFS_PASSWORD or a prompt
+    #[arg(long, conflicts_with = "token", global = true)]
+    pub user: Option<String>,
+
+    /// pre-shared token for AUTH
+    #[arg(long, env = "BFS_TOKEN", hide_env_values = true, global = true)]
+    pub token: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ClientCommand {
+    /// Mirror the server's mount into --out, fetching only new and changed files
+    Sync(SyncArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SyncArgs {
+    /// also delete local files and directories that are gone from the server
+    #[arg(long)]
+    pub delete: bool,
+
+    /// print what would be fetched and deleted, and change nothing
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+type BoxedReader = Box<dyn io::Read + Unpin + Send>;
+type BoxedWriter = Box<dyn io::Write + Unpin + Send>;
+
+/// One connection to the server, plain TCP or TLS. Reads are buffered since
+/// responses mix text lines with raw file bodies.
+pub struct Session {
+    reader: BufReader<BoxedReader>,
+    writer: BoxedWriter,
+    limit: Option<TokenBucket>, // --limit-rate for download bodies
+    protocol: u32, // 0 when the server predates HELLO
+    capabilities: Capabilities, // negotiated; the legacy set for version 0
+}
+
+impl Session {
+    fn new(reader: BoxedReader, writer: BoxedWriter, limit: Option<Rate>) -> Self {
+        Session {
+            reader: BufReader::new(reader),
+            writer,
+            limit: limit.map(TokenBucket::new),
+            protocol: 0,
+            capabilities: Capabilities::legacy(),
+        }
+    }
+
+    /// Protocol version agreed with the server.
+    pub fn protocol(&self) -> u32 {
+        self.protocol
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    fn require(&self, capability: Capability) -> io::Result<()> {
+        if self.supports(capability) {
+            return Ok(());
+        }
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("server does not support {}", capability)))
+    }
+
+    /// Account for `bytes` of received body and pause once the download
+    /// rate limit is used up; the server then stalls on a full TCP window.
+    async fn throttle(&mut self, bytes: usize) {
+        if let Some(bucket) = &mut self.limit {
+            bucket.consume(bytes);
+            let wait = bucket.wait_time(0);
+            if !wait.is_zero() {
+                async_std::task::sleep(wait).await;
+            }
+        }
+    }
+
+    /// Read some of a reply, giving up on a connection that has gone quiet
+    /// for `STALL_TIMEOUT`. Everything read from the server goes through
+    /// here or the two below, so a dead link always ends in an error that
+    /// `--retries` can act on.
+    async fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::timeout(STALL_TIMEOUT, self.reader.read(buf)).await
+    }
+
+    async fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        io::timeout(STALL_TIMEOUT, self.reader.read_line(line)).await
+    }
+
+    /// Fill `buf`; the timeout is on each read, so a slow but steady
+    /// sender is never cut off.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_some(&mut buf[filled..]).await? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed mid-response")),
+                n => filled += n,
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, cmd: &str) -> io::Result<()> {
+        self.writer.write_all(cmd.as_bytes()).await?;
+        self.writer.flush().await
+    }
 }
 
+/// One change `sync` makes to bring the local mirror up to date. Names are
+/// remote paths, which are also the paths under the mirror directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStep {
+    /// Missing locally.
+    New(Entry),
+    /// Different size, or different mtime and content.
+    Changed(Entry),
+    /// Same content with another mtime; only the local mtime is set.
+    Touch(Entry),
+    /// Gone from the server, or in the way of something of another type.
+    Delete(String),
+}
+
+impl fmt::Display for SyncStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncStep::New(entry) => write!(f, "new      {} ({} bytes)", entry.name, entry.size),
+            SyncStep::Changed(entry) => write!(f, "changed  {} ({} bytes)", entry.name, entry.size),
+            SyncStep::Touch(entry) => write!(f, "touch    {}", entry.name),
+            SyncStep::Delete(name) => write!(f, "delete   {}", name),
+        }
+    }
+}
+
+/// Outcome of one frame of an MGET batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchStatus {
+    Ok,
+    Mismatch,
+    /// The server could not send it, or it could not be written locally.
+    Error(String),
+}
+
+#[derive(Clone)]
 pub struct Client {
     addr: String,
+    hash: Option<Algorithm>,
+    compression: Option<Compression>,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    credentials: Option<Credentials>,
+    limit_rate: Option<Rate>,
+    connections: usize,
+    retries: u32,
+    retry_backoff: Duration,
 }
 
 impl Client {
     pub fn new(addr: &str) -> Self {
-        Self { addr: addr.to_string() }
+        Self { addr: addr.to_string(), hash: None, compression: None, tls: None, credentials: None, limit_rate: None, connections: 1, retries: 0, retry_backoff: Duration::from_secs(1) }
+    }
+
+    /// Download single files over `connections` connections at once, each
+    /// fetching a byte range. Servers without ranged GET get one connection.
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Reconnect and resume a GET that fails on a dropped connection, up to
+    /// `retries` times, waiting `backoff` before the first attempt and
+    /// doubling the wait for each one after.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Send `AUTH` with these credentials right after connecting.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Wrap every connection in TLS with the given configuration.
+    pub fn with_tls(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub async fn connect(&self) -> io::Result<Session> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let mut session = match &self.tls {
+            None => Session::new(Box::new(stream.clone()), Box::new(stream), self.limit_rate),
+            Some(config) => {
+                let stream = TlsConnector::from(Arc::clone(config))
+                    .connect(server_name(&self.addr)?, stream)
+                    .await?;
+                let (reader, writer) = futures_lite::io::split(stream);
+                Session::new(Box::new(reader), Box::new(writer), self.limit_rate)
+            }
+        };
+        self.hello(&mut session).await?;
+        // A server without authentication does not negotiate `auth`
+        if let Some(credentials) = &self.credentials
+            && session.supports(Capability::Auth)
+        {
+            self.authenticate(&mut session, credentials).await?;
+        }
+        Ok(session)
+    }
+
+    /// Offer our version and capabilities. A server that answers `ERR`
+    /// predates `HELLO`, and the session stays at version 0.
+    async fn hello(&self, session: &mut Session) -> io::Result<()> {
+        let ours = Hello { version: PROTOCOL_VERSION, capabilities: Capabilities::all() };
+        let sent = session.send(&format!("{}\n", ours)).await;
+        // A server at its connection limit says so and hangs up, which may
+        // already have failed the send
+        let mut reply = String::new();
+        let read = session.read_line(&mut reply).await;
+        let reply = reply.trim_end();
+        if reply == "ERR busy" {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "server is busy"));
+        }
+        sent?;
+        read?;
+        if reply.starts_with("ERR") {
+            info!(reply, "server does not negotiate, using protocol version 0");
+            return Ok(());
+        }
+        let theirs = reply.parse::<Hello>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        session.protocol = theirs.version.min(PROTOCOL_VERSION);
+        session.capabilities = ours.capabilities.intersection(theirs.capabilities);
+        debug!(version = session.protocol, capabilities = %session.capabilities, "negotiated protocol");
+        Ok(())
+    }
+
+    async fn authenticate(&self, session: &mut Session, credentials: &Credentials) -> io::Result<()> {
+        session.send(&format!("{}\n", credentials.command())).await?;
+        let mut reply = String::new();
+        session.read_line(&mut reply).await?;
+        let reply = reply.trim_end();
+        if !reply.starts_with("OK") {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("server rejected credentials: {}", reply)));
+        }
+        Ok(())
+    }
+
+    /// The hash to request on `session`. Asking for one the server did not
+    /// negotiate is an error rather than a quiet fall back to MD5.
+    fn hash_for(&self, session: &Session) -> io::Result<Option<Algorithm>> {
+        if let Some(capability) = self.hash.and_then(Capability::for_hash) {
+            session.require(capability)?;
+        }
+        Ok(self.hash)
+    }
+
+    /// Compression only saves bandwidth, so without it we fetch uncompressed.
+    fn compression_for(&self, session: &Session) -> Option<Compression> {
+        let compression = self.compression?;
+        if session.supports(Capability::for_compression(compression)) {
+            return Some(compression);
+        }
+        warn!(%compression, "server does not support compression, fetching uncompressed");
+        None
+    }
+
+    /// Ask the server for `algorithm` instead of the legacy MD5 trailer.
+    pub fn with_hash(mut self, algorithm: Algorithm) -> Self {
+        self.hash = Some(algorithm);
+        self
+    }
+
+    /// Read download bodies no faster than `rate`.
+    pub fn with_limit_rate(mut self, rate: Rate) -> Self {
+        self.limit_rate = Some(rate);
+        self
+    }
+
+    /// Ask the server to compress GET bodies on the wire.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
     }
 
     pub async fn run_cli(cli: ClientCli) -> io::Result<()> {
-        let client = Client::new(&cli.addr);
-        let mut stream = TcpStream::connect(&client.addr).await?;
-        println!("Connected to {}", client.addr);
-        client.list_and_get(&mut stream, cli.get, cli.out).await
-    }
-
-    pub async fn list_and_get(&self, stream: &mut TcpStream, get_filename: Option<String>, out_dir: Option<PathBuf>) -> io::Result<()> {
-        println!("listing and getting");
-        // ask for LIST
-        writeln!(stream, "{}", "LIST").await?;
-        stream.flush().await?;
-        println!("LIST sent");
-        
-        let mut reader = BufReader::new(stream.clone());
-        let mut filenames = Vec::new();
-        loop {
+        let addr = cli.addr.as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "--addr is required"))?;
+        let mut client = Client::new(addr);
+        if let Some(algorithm) = cli.hash {
+            client = client.with_hash(algorithm);
+        }
+        if let Some(compression) = cli.compress {
+            client = client.with_compression(compression);
+        }
+        if let Some(rate) = cli.limit_rate {
+            client = client.with_limit_rate(rate);
+        }
+        client = client.with_connections(cli.connections)
+            .with_retries(cli.retries, Duration::from_secs(cli.retry_backoff));
+        if cli.tls || cli.insecure || cli.ca.is_some() {
+            client = client.with_tls(crate::tls::client_config(cli.ca.as_deref(), cli.insecure)?);
+        }
+        if let Some(token) = cli.token {
+            client = client.with_credentials(Credentials::Token(token));
+        } else if let Some(user) = cli.user {
+            let password = match std::env::var("BFS_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => crate::auth::read_password(&format!("Password for {}: ", user))?,
+            };
+            client = client.with_credentials(Credentials::Password { user, password });
+        }
+        let mut session = client.connect().await?;
+        info!(addr = %client.addr, "connected");
+        if let Some(ClientCommand::Sync(args)) = cli.command {
+            let out_dir = cli.out
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "sync needs --out"))?;
+            return client.run_sync(&mut session, &out_dir, &args).await;
+        }
+        if let Some(path) = cli.put {
+            return client.put(&mut session, &path).await;
+        }
+        if let Some(dir) = cli.getdir {
+            let out_dir = cli.out.unwrap_or_else(|| PathBuf::from("."));
+            return client.get_dir(&mut session, &dir, cli.format, &out_dir).await;
+        }
+        if !cli.mget.is_empty() {
+            let out_dir = cli.out.unwrap_or_else(|| PathBuf::from("."));
+            let results = client.mget(&mut session, &cli.mget, &out_dir).await?;
+            let mut ok = 0;
+            for (name, status) in &results {
+                match status {
+                    FetchStatus::Ok => {
+                        ok += 1;
+                        println!("OK       {}", name);
+                    }
+                    FetchStatus::Mismatch => println!("MISMATCH {}", name),
+                    FetchStatus::Error(e) => println!("ERR      {}: {}", name, e),
+                }
+            }
+            println!("{}/{} files OK", ok, results.len());
+            if ok < results.len() {
+                return Err(io::Error::other(format!("{} files failed to download", results.len() - ok)));
+            }
+            return Ok(());
+        }
+        let out_dir = cli.out.as_deref().unwrap_or(Path::new("."));
+        match cli.get {
+            Some(filename) => client.list_and_get(&mut session, filename, out_dir, cli.recursive, cli.long).await,
+            None if cli.recursive || cli.long => client.print_listing(&mut session, out_dir, cli.recursive, cli.long).await,
+            None => crate::shell::run(&client, session, cli.out.as_deref()).await,
+        }
+    }
+
+    /// Plain `LIST [-r]`: file paths only.
+    pub async fn list(&self, session: &mut Session, recursive: bool) -> io::Result<Vec<String>> {
+        let cmd = if recursive { "LIST -r\n" } else { "LIST\n" };
+        let lines = list_lines(session, cmd).await?;
+        Ok(lines.iter().map(|line| unescape_name(line)).collect())
+    }
+
+    /// Extended `LIST -l [-r]` with size, mtime, type and cached checksum.
+    pub async fn list_long(&self, session: &mut Session, recursive: bool) -> io::Result<Vec<Entry>> {
+        session.require(Capability::ListLong)?;
+        let cmd = if recursive { "LIST -lr\n" } else { "LIST -l\n" };
+        list_lines(session, cmd).await?
+            .iter()
+            .map(|line| line.parse::<Entry>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+            .collect()
+    }
+
+    /// `LIST -l` of one directory; `""` is the root of the mount.
+    pub async fn list_dir(&self, session: &mut Session, dir: &str) -> io::Result<Vec<Entry>> {
+        session.require(Capability::ListLong)?;
+        let cmd = match dir {
+            "" => "LIST -l\n".to_string(),
+            dir => format!("LIST -l {}\n", escape_name(dir)),
+        };
+        list_lines(session, &cmd).await?
+            .iter()
+            .map(|line| line.parse::<Entry>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+            .collect()
+    }
+
+    /// The `LIST -l` entry for `name`, with `STAT` or else found in a
+    /// listing of its directory. None if the server has no such file or
+    /// directory, or will not say.
+    pub async fn stat(&self, session: &mut Session, name: &str) -> io::Result<Option<Entry>> {
+        if session.supports(Capability::Stat) {
+            session.send(&format!("STAT {}\n", escape_name(name))).await?;
             let mut line = String::new();
-            let n = reader.read_line(&mut line).await?;
-            if n == 0 { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed")); }
+            if session.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed"));
+            }
             let line = line.trim_end();
-            if line == "." { break; }
-            println!("- {}", line);
-            filenames.push(line.to_string());
+            if line.starts_with("ERR ") {
+                debug!(name, reply = line, "STAT failed");
+                return Ok(None);
+            }
+            return line.parse::<Entry>().map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let dir = name.rsplit_once('/').map_or("", |(dir, _)| dir);
+        match self.list_dir(session, dir).await {
+            Ok(entries) => Ok(entries.into_iter().find(|entry| entry.name == name)),
+            // list_lines reports the server's ERR lines as Other
+            Err(e) if e.kind() == io::ErrorKind::Other => Ok(None),
+            Err(e) => Err(e),
         }
+    }
 
-        let filename = match get_filename {
-            Some(f) => f,
-            None => {
-                println!("Pick a file to GET (type exact name):");
-                let mut s = String::new();
-                io::stdin().read_line(&mut s).await?;
-                s.trim().to_string()
+    /// Upload `path` into the root of the mount under its own file name.
+    pub async fn put(&self, session: &mut Session, path: &Path) -> io::Result<()> {
+        let name = path.file_name().and_then(|n| n.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid file name: {:?}", path)))?;
+        self.put_as(session, path, name).await
+    }
+
+    /// Upload `path` as `name`, which may include directories under the mount.
+    pub async fn put_as(&self, session: &mut Session, path: &Path, name: &str) -> io::Result<()> {
+        session.require(Capability::Put)?;
+        let hash = self.hash_for(session)?;
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        let cmd = match hash {
+            Some(algorithm) => format!("PUT {} {} HASH={}\n", escape_name(name), size, algorithm),
+            None => format!("PUT {} {}\n", escape_name(name), size),
+        };
+        session.writer.write_all(cmd.as_bytes()).await?;
+        println!("Sending {} bytes...", size);
+
+        let mut buf = vec![0u8; 64 * 1024];  // 64KB buffer
+        let mut total_sent = 0u64;
+        let mut checksum = hash.unwrap_or_default().hasher();
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 { break; }
+            checksum.update(&buf[..n]);
+            session.writer.write_all(&buf[..n]).await?;
+            total_sent += n as u64;
+
+            // Show progress every 1MB or at the end
+            if total_sent.is_multiple_of(1024 * 1024) || total_sent == size {
+                print!("\rUploaded {}/{} bytes ({:.1}%)",
+                    total_sent, size, (total_sent as f64 / size as f64) * 100.0);
+                std::io::stdout().flush()?;
             }
+        }
+        println!();
+        if total_sent != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                format!("file changed while uploading (sent {}/{} bytes)", total_sent, size)));
+        }
+
+        // newline after file, then the MD5/HASH trailer
+        let digest_hex = checksum.hex_digest();
+        let trailer = match hash {
+            Some(algorithm) => format!("\nHASH {} {}\n", algorithm, digest_hex),
+            None => format!("\nMD5 {}\n", digest_hex),
         };
+        session.send(&trailer).await?;
+
+        let mut reply = String::new();
+        session.read_line(&mut reply).await?;
+        let reply = reply.trim_end();
+        if reply.starts_with("OK") {
+            println!("Upload OK, {}: {}", checksum.algorithm(), digest_hex);
+            return Ok(());
+        }
+        println!("Server error: {}", reply);
+        // `ERR <alg> mismatch <server hex>` if the body arrived damaged
+        let algorithm = checksum.algorithm();
+        if let Some(server) = reply.strip_prefix(&format!("ERR {} mismatch ", algorithm)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, HashMismatch { algorithm, server: server.to_string(), local: digest_hex }));
+        }
+        Err(io::Error::other(format!("server error: {}", reply.strip_prefix("ERR ").unwrap_or(reply))))
+    }
+
+    /// Fetch every file matching `patterns` in a single `MGET`, writing them
+    /// under `out_dir` with their remote layout. Returns one status per frame,
+    /// keyed by file name (or by pattern, for patterns the server rejected).
+    pub async fn mget(&self, session: &mut Session, patterns: &[String], out_dir: &Path) -> io::Result<Vec<(String, FetchStatus)>> {
+        session.require(Capability::Mget)?;
+        let hash = self.hash_for(session)?;
+        let mut cmd = String::from("MGET");
+        for pattern in patterns {
+            cmd.push(' ');
+            cmd.push_str(&escape_name(pattern));
+        }
+        if let Some(algorithm) = hash {
+            cmd.push_str(&format!(" HASH={}", algorithm));
+        }
+        cmd.push('\n');
+        session.send(&cmd).await?;
+
+        let mut header = String::new();
+        session.read_line(&mut header).await?;
+        let header = header.trim_end();
+        let count = match header.strip_prefix("MGET ").map(str::parse::<usize>) {
+            Some(Ok(count)) => count,
+            _ if header.starts_with("ERR ") => return Err(io::Error::other(format!("server error: {}", &header[4..]))),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected header: {}", header))),
+        };
+
+        let mut results = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut line = String::new();
+            session.read_line(&mut line).await?;
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (name, size) = match fields[..] {
+                // `DIGEST=` may follow, which the trailer repeats
+                ["MFILE", name, size, ..] => (unescape_name(name), size.parse::<u64>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
+                ["ERR", name, ..] => {
+                    results.push((unescape_name(name), FetchStatus::Error(fields[2..].join(" "))));
+                    continue;
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected frame: {}", line.trim_end()))),
+            };
+
+            // A file we cannot write still has to be read off the wire
+            let mut checksum = hash.unwrap_or_default().hasher();
+            let file = local_path(out_dir, &name).and_then(|path| {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                File::create(path)
+            });
+            let write_error = match file {
+                Ok(mut file) => read_body(session, &mut file, size, checksum.as_mut()).await?.err(),
+                Err(e) => {
+                    read_body(session, &mut std::io::sink(), size, checksum.as_mut()).await??;
+                    Some(e)
+                }
+            };
+            let (algorithm, server_hex) = read_trailer(session).await?;
+            let status = if let Some(e) = write_error {
+                FetchStatus::Error(e.to_string())
+            } else if algorithm == checksum.algorithm() && checksum.hex_digest().eq_ignore_ascii_case(&server_hex) {
+                FetchStatus::Ok
+            } else {
+                FetchStatus::Mismatch
+            };
+            results.push((name, status));
+        }
+        Ok(results)
+    }
+
+    /// Compare a recursive listing of the mount with the mirror in `out_dir`
+    /// and work out what `sync` has to change. Files with the same size and
+    /// mtime are taken as unchanged; if only the mtime differs, the MD5 the
+    /// server has cached decides, and without one the file is fetched again.
+    /// With `delete`, local entries the server does not list go as well.
+    pub async fn sync_plan(&self, session: &mut Session, out_dir: &Path, delete: bool) -> io::Result<Vec<SyncStep>> {
+        let remote = self.list_long(session, true).await?;
+        let mut steps = Vec::new();
+        for entry in &remote {
+            let path = local_path(out_dir, &entry.name)?;
+            let local = std::fs::symlink_metadata(&path).ok();
+            match local {
+                Some(metadata) if metadata.is_dir() != (entry.kind == EntryKind::Dir) => {
+                    steps.push(SyncStep::Delete(entry.name.clone()));
+                    if entry.kind != EntryKind::Dir {
+                        steps.push(SyncStep::New(entry.clone()));
+                    }
+                }
+                _ if entry.kind == EntryKind::Dir => {}
+                None => steps.push(SyncStep::New(entry.clone())),
+                Some(metadata) => steps.extend(compare_local(&path, &metadata, entry)?),
+            }
+        }
+        if delete {
+            let kinds = remote.iter().map(|entry| (entry.name.as_str(), entry.kind)).collect();
+            let mut extra = Vec::new();
+            local_extras(out_dir, out_dir, &kinds, &mut extra)?;
+            steps.extend(extra.into_iter().map(SyncStep::Delete));
+        }
+        Ok(steps)
+    }
+
+    /// Carry out a `sync_plan` under `out_dir`: deletions first, then mtimes,
+    /// then the downloads in as few MGETs as fit on a command line. Fetched
+    /// files take the server's mtime, so the next plan sees them as unchanged.
+    pub async fn apply_sync(&self, session: &mut Session, out_dir: &Path, steps: &[SyncStep]) -> io::Result<Vec<(String, FetchStatus)>> {
+        for step in steps {
+            if let SyncStep::Delete(name) = step {
+                let path = local_path(out_dir, name)?;
+                let removed = match std::fs::symlink_metadata(&path) {
+                    Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&path),
+                    Ok(_) => std::fs::remove_file(&path),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = removed
+                    && e.kind() != io::ErrorKind::NotFound
+                {
+                    return Err(e);
+                }
+            }
+        }
+        for step in steps {
+            if let SyncStep::Touch(entry) = step {
+                set_mtime(&local_path(out_dir, &entry.name)?, entry.mtime)?;
+            }
+        }
+
+        let fetches = steps.iter()
+            .filter_map(|step| match step {
+                SyncStep::New(entry) | SyncStep::Changed(entry) => Some(entry),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mtimes = fetches.iter().map(|entry| (entry.name.as_str(), entry.mtime)).collect::<HashMap<_, _>>();
+        let mut results = Vec::with_capacity(fetches.len());
+        let mut batch = Vec::new();
+        let mut batch_len = 0;
+        for entry in &fetches {
+            let pattern = literal_pattern(&entry.name);
+            let len = escape_name(&pattern).len() + 1;
+            // Leave room for `MGET` and a `HASH=` option
+            if !batch.is_empty() && batch_len + len > MAX_COMMAND_LEN - 64 {
+                results.extend(self.mget(session, &batch, out_dir).await?);
+                batch.clear();
+                batch_len = 0;
+            }
+            batch_len += len;
+            batch.push(pattern);
+        }
+        if !batch.is_empty() {
+            results.extend(self.mget(session, &batch, out_dir).await?);
+        }
+        for (name, status) in &results {
+            if *status == FetchStatus::Ok
+                && let Some(mtime) = mtimes.get(name.as_str())
+            {
+                set_mtime(&local_path(out_dir, name)?, *mtime)?;
+            }
+        }
+        Ok(results)
+    }
+
+    /// `client sync`: print the plan, then carry it out unless it is a dry run.
+    async fn run_sync(&self, session: &mut Session, out_dir: &Path, args: &SyncArgs) -> io::Result<()> {
+        let steps = self.sync_plan(session, out_dir, args.delete).await?;
+        if steps.is_empty() {
+            println!("{} is up to date", out_dir.display());
+            return Ok(());
+        }
+        for step in &steps {
+            println!("{}", step);
+        }
+        if args.dry_run {
+            println!("{} changes planned (dry run, nothing changed)", steps.len());
+            return Ok(());
+        }
+
+        let results = self.apply_sync(session, out_dir, &steps).await?;
+        let mut failed = 0;
+        for (name, status) in &results {
+            match status {
+                FetchStatus::Ok => continue,
+                FetchStatus::Mismatch => println!("MISMATCH {}", name),
+                FetchStatus::Error(e) => println!("ERR      {}: {}", name, e),
+            }
+            failed += 1;
+        }
+        println!("{} changes, {}/{} files fetched OK", steps.len(), results.len() - failed, results.len());
+        if failed > 0 {
+            return Err(io::Error::other(format!("{} files failed to sync", failed)));
+        }
+        Ok(())
+    }
 
-        // Send GET using the same stream
-        let cmd = format!("GET {}
-", filename);
-        stream.write_all(cmd.as_bytes()).await?;
-        stream.flush().await?;
+    /// Fetch `dir` with `GETDIR` and unpack it under `out_dir` while it
+    /// streams in, keeping the remote layout. Nothing is staged on disk.
+    pub async fn get_dir(&self, session: &mut Session, dir: &str, mut format: ArchiveFormat, out_dir: &Path) -> io::Result<()> {
+        session.require(Capability::Getdir)?;
+        let hash = self.hash_for(session)?;
+        if format == ArchiveFormat::TarZst && !session.supports(Capability::Zstd) {
+            warn!("server does not support zstd, fetching a plain tar archive");
+            format = ArchiveFormat::Tar;
+        }
+        let mut cmd = format!("GETDIR {} {}", escape_name(dir), format);
+        if let Some(algorithm) = hash {
+            cmd.push_str(&format!(" HASH={}", algorithm));
+        }
+        cmd.push('\n');
+        session.send(&cmd).await?;
 
-        // We'll use the reader to parse the FILE header
         let mut header = String::new();
-        reader.read_line(&mut header).await?;
+        session.read_line(&mut header).await?;
+        let header = header.trim_end();
+        if let Some(err) = header.strip_prefix("ERR ") {
+            return Err(io::Error::other(format!("server error: {}", err)));
+        }
+        if header != format!("ARCHIVE {}", format) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected header: {}", header)));
+        }
+
+        // tar is a blocking reader, so unpack on a thread fed with the chunks
+        std::fs::create_dir_all(out_dir)?;
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(16);
+        let unpack_dir = out_dir.to_path_buf();
+        let unpacker = std::thread::spawn(move || -> io::Result<()> {
+            let reader = ChannelReader { rx, chunk: Vec::new(), pos: 0 };
+            match format {
+                ArchiveFormat::Tar => tar::Archive::new(reader).unpack(&unpack_dir),
+                ArchiveFormat::TarZst => tar::Archive::new(zstd::stream::read::Decoder::new(reader)?).unpack(&unpack_dir),
+            }
+        });
+
+        let mut checksum = hash.unwrap_or_default().hasher();
+        let mut total_read = 0u64;
+        while let Some(chunk) = read_data_chunk(session).await? {
+            let len = chunk.len();
+            checksum.update(&chunk);
+            total_read += len as u64;
+            // The unpacker stops reading at the end-of-archive marker, or on
+            // error; keep draining so the session stays usable
+            let _ = tx.send(chunk);
+
+            // Show progress every 1MB
+            if total_read / (1024 * 1024) != (total_read - len as u64) / (1024 * 1024) {
+                print!("\rReceived {} bytes", total_read);
+                std::io::stdout().flush()?;
+            }
+        }
+        println!("\rReceived {} bytes", total_read);
+        drop(tx);
+
+        // No blank line here: the DATA framing already delimits the body
+        let mut hash_line = String::new();
+        session.read_line(&mut hash_line).await?;
+        let (algorithm, server_hex) = parse_hash_trailer(&hash_line)?;
+        let unpacked = unpacker.join().map_err(|_| io::Error::other("unpack thread panicked"))?;
+        // A damaged archive may still unpack, so the digest decides first
+        report_hash(checksum.as_ref(), algorithm, &server_hex)?;
+        unpacked?;
+        println!("Unpacked {} into {:?}", dir, out_dir);
+        Ok(())
+    }
+
+    /// Print the listing of the mount, then GET `filename` under `out_dir`.
+    pub async fn list_and_get(&self, session: &mut Session, filename: String, out_dir: &Path, recursive: bool, long: bool) -> io::Result<()> {
+        trace!("listing and getting");
+        self.print_listing(session, out_dir, recursive, long).await?;
+
+        // Remote paths may be nested; recreate the same layout under out_dir
+        let out_path = local_path(out_dir, &filename)?;
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.get(session, &filename, &out_path).await
+    }
+
+    /// Print `LIST [-l] [-r]` of the mount. Long entries show how the copy
+    /// under `out_dir`, if any, compares.
+    pub async fn print_listing(&self, session: &mut Session, out_dir: &Path, recursive: bool, long: bool) -> io::Result<()> {
+        if long {
+            let entries = self.list_long(session, recursive).await?;
+            for entry in &entries {
+                let kind = match entry.kind {
+                    EntryKind::File => '-',
+                    EntryKind::Dir => 'd',
+                    EntryKind::Symlink => 'l',
+                };
+                println!("{} {:>12} {:>11} {}{}", kind, entry.size, entry.mtime, entry.name, local_status(out_dir, entry));
+            }
+        } else {
+            for name in self.list(session, recursive).await? {
+                println!("- {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// GET `name` into `out_path`, resuming from a partial local copy if
+    /// the server supports ranged GET; a copy the whole-file digest shows
+    /// was not a prefix of the file is fetched again from zero. With retries enabled, a dropped
+    /// connection is reopened and the download picks up from the bytes
+    /// already written, unless the file's size or mtime on the server has
+    /// changed meanwhile; then it starts over rather than splice two
+    /// versions together. Segmented downloads always start over. A local
+    /// copy matching the digest the server announces is kept as it is.
+    pub async fn get(&self, session: &mut Session, name: &str, out_path: &Path) -> io::Result<()> {
+        if self.have_copy(session, name, out_path).await? {
+            return Ok(());
+        }
+        let mut version = match self.retries {
+            0 => None,
+            _ => self.remote_version(session, name).await?,
+        };
+        let mut result = self.get_once(session, name, out_path).await;
+        let mut attempt = 0;
+        while let Err(e) = &result
+            && attempt < self.retries
+            && is_dropped_connection(e)
+        {
+            attempt += 1;
+            let delay = self.retry_backoff.saturating_mul(1 << (attempt - 1).min(16)).min(MAX_RETRY_BACKOFF);
+            warn!(error = %e, attempt, retries = self.retries, ?delay, "connection lost, retrying");
+            async_std::task::sleep(delay).await;
+            result = self.resume(session, name, out_path, &mut version).await;
+        }
+        result
+    }
+
+    /// Reconnect and carry on with an interrupted GET.
+    async fn resume(&self, session: &mut Session, name: &str, out_path: &Path, version: &mut Option<(u64, u64)>) -> io::Result<()> {
+        *session = self.connect().await?;
+        let current = self.remote_version(session, name).await?;
+        if current != *version {
+            warn!(name, "file changed on the server, restarting from zero");
+            File::create(out_path)?;
+            *version = current;
+        }
+        self.get_once(session, name, out_path).await
+    }
+
+    /// Size and mtime of `name` as `LIST -l` of its directory reports them,
+    /// or None when the server cannot tell us.
+    async fn remote_version(&self, session: &mut Session, name: &str) -> io::Result<Option<(u64, u64)>> {
+        if !session.supports(Capability::ListLong) {
+            return Ok(None);
+        }
+        // A missing file is for the GET that follows to report
+        let entry = self.stat(session, name).await?;
+        Ok(entry.map(|entry| (entry.size, entry.mtime)))
+    }
+
+    /// Whether `out_path` already holds `name`, by the digest the server
+    /// announces in the header of an empty range. Servers only announce
+    /// digests they have to hand, so without one the file is fetched.
+    async fn have_copy(&self, session: &mut Session, name: &str, out_path: &Path) -> io::Result<bool> {
+        let local_len = match std::fs::metadata(out_path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() > 0 => metadata.len(),
+            _ => return Ok(false),
+        };
+        if ![Capability::Digest, Capability::Range, Capability::HashNone].into_iter().all(|capability| session.supports(capability)) {
+            return Ok(false);
+        }
+        let header = request_file(session, name, 0, Some(0), None, false, None).await?;
+        if header.starts_with("ERR") {
+            return Ok(false); // for the GET that follows to report
+        }
+        let FileHeader { total, digest, .. } = parse_file_header(&header)?;
+        // Only the newline after the empty body
+        session.read_exact(&mut [0u8; 1]).await?;
+        let Some((algorithm, server_hex)) = digest.filter(|_| total == local_len) else {
+            return Ok(false);
+        };
+        let mut checksum = algorithm.hasher();
+        hash_prefix(&mut File::open(out_path)?, local_len, checksum.as_mut())?;
+        if !checksum.hex_digest().eq_ignore_ascii_case(&server_hex) {
+            debug!(name, "local copy differs from the server's");
+            return Ok(false);
+        }
+        println!("Already have {} ({} bytes), {} OK: {}", name, local_len, algorithm.name().to_uppercase(), server_hex);
+        Ok(true)
+    }
+
+    async fn get_once(&self, session: &mut Session, filename: &str, out_path: &Path) -> io::Result<()> {
+        if self.connections > 1 {
+            if session.supports(Capability::Range) {
+                return self.get_segmented(session, filename, out_path).await;
+            }
+            warn!("server does not support ranged GET, downloading over one connection");
+        }
+
+        // Resume from a partial local copy if there is one
+        let mut local_len = std::fs::metadata(out_path).map(|m| m.len()).unwrap_or(0);
+        if local_len > 0 && !session.supports(Capability::Range) {
+            warn!("server does not support ranged GET, restarting from zero");
+            local_len = 0;
+        }
+        match self.get_from(session, filename, out_path, local_len).await {
+            // The local copy was not a prefix of this file after all, e.g. an
+            // older version of it; splicing the two together cannot be kept
+            Err(e) if local_len > 0 && is_hash_mismatch(&e) => {
+                warn!(name = filename, "resumed download does not match the server's digest, restarting from zero");
+                self.get_from(session, filename, out_path, 0).await
+            }
+            result => result,
+        }
+    }
+
+    /// GET `filename` into `out_path`, asking for the bytes after the first
+    /// `local_len`, which are kept.
+    async fn get_from(&self, session: &mut Session, filename: &str, out_path: &Path, local_len: u64) -> io::Result<()> {
+        let hash = self.hash_for(session)?;
+        let compression = self.compression_for(session);
+        let mut header = request_file(session, filename, local_len, None, hash, true, compression).await?;
+        if header.starts_with("ERR") && local_len > 0 {
+            // e.g. the local copy is longer than the file on the server
+            warn!(reply = header.trim_end(), "cannot resume, restarting from zero");
+            header = request_file(session, filename, 0, None, hash, true, compression).await?;
+        }
         if header.starts_with("ERR") {
             println!("Server error: {}", header);
             return Ok(());
         }
-        if !header.starts_with("FILE ") {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected header: {}", header)));
+        let FileHeader { size, offset, total, compression, compressed, digest } = parse_file_header(&header)?;
+        if let Some((algorithm, hex)) = &digest {
+            debug!(%algorithm, digest = %hex, "server announced the digest");
         }
-        let size_str = header[5..].trim();
-        let size: usize = size_str.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-        println!("Receiving {} bytes...", size);
+        // The hash trailer covers the whole file, so seed it with the bytes we already have
+        let mut checksum = hash.unwrap_or_default().hasher();
+        let mut file = if offset > 0 {
+            println!("Resuming at byte {}, receiving {} bytes...", offset, size);
+            let mut file = OpenOptions::new().read(true).write(true).open(out_path)?;
+            file.set_len(offset)?;
+            hash_prefix(&mut file, offset, checksum.as_mut())?;
+            file
+        } else {
+            println!("Receiving {} bytes...", size);
+            File::create(out_path)?
+        };
 
-        // Create output file
-        let out_dir = out_dir.unwrap_or_else(|| PathBuf::from("."));
-        let mut file = File::create(out_dir.join(&filename))?;
+        if let Some(compression) = compression {
+            // `compressed` bytes on the wire; size, progress and hash refer to the uncompressed file
+            let Some(compressed) = compressed else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("compressed header without a length: {}", header)));
+            };
+            let mut decompressor = compression.decompressor()?;
+            let mut received = 0u64;
+            let mut total_written = 0u64;
+            let mut write_plain = |plain: &[u8]| -> io::Result<()> {
+                checksum.update(plain);
+                file.write_all(plain)?;
+                let before = total_written;
+                total_written += plain.len() as u64;
+
+                // Show progress every 1MB
+                if total_written / (1024 * 1024) != before / (1024 * 1024) {
+                    let done = offset + total_written;
+                    print!("\rDownloaded {}/{} bytes ({:.1}%)",
+                        done, total, (done as f64 / total as f64) * 100.0);
+                    std::io::stdout().flush()?;
+                }
+                Ok(())
+            };
+            let mut buf = vec![0u8; 64 * 1024];
+            while received < compressed {
+                let to_read = std::cmp::min(buf.len() as u64, compressed - received) as usize;
+                let n = session.read_some(&mut buf[..to_read]).await?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        format!("server closed while sending file (got {}/{} compressed bytes)", received, compressed)));
+                }
+                received += n as u64;
+                session.throttle(n).await;
+                decompressor.write(&buf[..n])?;
+                write_plain(&decompressor.take_output())?;
+            }
+            write_plain(&decompressor.finish()?)?;
+            let done = offset + total_written;
+            println!("\rDownloaded {}/{} bytes ({:.1}%)", done, total, (done as f64 / total as f64) * 100.0);
+            info!(%compression, size = total_written, wire_bytes = received, "compressed transfer");
+            if total_written != size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("decompressed {} bytes, expected {}", total_written, size)));
+            }
+
+            let (algorithm, server_hex) = read_trailer(session).await?;
+            return report_hash(checksum.as_ref(), algorithm, &server_hex);
+        }
 
         // Read exactly size bytes in chunks and show progress
         let mut remaining = size;
         let mut buf = vec![0u8; 64 * 1024];  // 64KB buffer
-        let mut total_read = 0usize;
-        let mut context = Context::new();
+        let mut total_read = 0u64;
 
         while remaining > 0 {
-            let to_read = std::cmp::min(buf.len(), remaining);
-            let n = reader.read(&mut buf[..to_read]).await?;
+            let to_read = std::cmp::min(buf.len() as u64, remaining) as usize;
+            let n = session.read_some(&mut buf[..to_read]).await?;
             if n == 0 { 
                 return Err(io::Error::new(io::ErrorKind::UnexpectedEof, 
                     format!("server closed while sending file (got {}/{} bytes)", total_read, size))); 
             }
-            context.consume(&buf[..n]);
+            checksum.update(&buf[..n]);
             file.write_all(&buf[..n])?;
-            total_read += n;
-            remaining -= n;
+            total_read += n as u64;
+            remaining -= n as u64;
+            session.throttle(n).await;
             
             // Show progress every 1MB or at the end
-            if total_read % (1024 * 1024) == 0 || remaining == 0 {
+            if total_read.is_multiple_of(1024 * 1024) || remaining == 0 {
+                let done = offset + total_read;
                 print!("\rDownloaded {}/{} bytes ({:.1}%)", 
-                    total_read, size, (total_read as f64 / size as f64) * 100.0);
-                use std::io::Write as _;
+                    done, total, (done as f64 / total as f64) * 100.0);
                 std::io::stdout().flush()?;
             }
         }
         println!();
 
-        // read trailing newline
-        let mut nl = [0u8; 1];
-        reader.read_exact(&mut nl).await?;
+        let (algorithm, server_hex) = read_trailer(session).await?;
+        report_hash(checksum.as_ref(), algorithm, &server_hex)
+    }
+
+    /// Fetch `name` into `out_path` over several connections at once, each
+    /// GETting its own byte range into a file preallocated at full size.
+    /// The first range goes over `session`; the whole-file digest is
+    /// checked at the end as for a plain GET.
+    async fn get_segmented(&self, session: &mut Session, name: &str, out_path: &Path) -> io::Result<()> {
+        let hash = self.hash_for(session)?;
+        if self.compression.is_some() {
+            warn!("compression is not used for segmented downloads");
+        }
+        // An empty range tells us the size and the digest, which every
+        // ranged GET sends for the whole file
+        let header = request_file(session, name, 0, Some(0), hash, true, None).await?;
+        if header.starts_with("ERR") {
+            println!("Server error: {}", header);
+            return Ok(());
+        }
+        let FileHeader { total, .. } = parse_file_header(&header)?;
+        let (algorithm, digest) = read_trailer(session).await?;
+        let download = Arc::new(SegmentedDownload {
+            name: name.to_string(),
+            path: out_path.to_path_buf(),
+            hash,
+            total,
+            algorithm,
+            digest,
+            received: AtomicU64::new(0),
+            finished: AtomicUsize::new(0),
+        });
+
+        let segments = self.connections.min(total.div_ceil(MIN_SEGMENT) as usize).max(1);
+        let segment_len = total.div_ceil(segments as u64).max(1);
+        File::create(out_path)?.set_len(total)?;
+        println!("Receiving {} bytes over {} connections...", total, segments);
 
-        // read MD5 line
-        let mut md5_line = String::new();
-        reader.read_line(&mut md5_line).await?;
-        if !md5_line.starts_with("MD5 ") {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected MD5, got: {}", md5_line)));
+        // --limit-rate caps the download as a whole
+        let mut client = self.clone();
+        client.limit_rate = self.limit_rate.map(|rate| Rate((rate.0 / segments as u64).max(1)));
+        session.limit = client.limit_rate.map(TokenBucket::new);
+
+        let mut ranges = (0..segments as u64)
+            .map(|i| (i * segment_len, segment_len.min(total - i * segment_len)))
+            .collect::<Vec<_>>()
+            .into_iter();
+        let (first_start, first_len) = ranges.next().unwrap_or_default();
+        let others = ranges.map(|(start, len)| {
+            let (client, download) = (client.clone(), Arc::clone(&download));
+            async_std::task::spawn(async move {
+                let result = async {
+                    let mut session = client.connect().await?;
+                    download.fetch(&mut session, start, len).await
+                }.await;
+                download.finished.fetch_add(1, Ordering::Relaxed);
+                result
+            })
+        }).collect::<Vec<_>>();
+
+        let first = async {
+            let result = download.fetch(session, first_start, first_len).await;
+            download.finished.fetch_add(1, Ordering::Relaxed);
+            result
+        };
+        let others = async {
+            let mut results = Vec::with_capacity(others.len());
+            for task in others {
+                results.push(task.await);
+            }
+            results
+        };
+        let show_progress = async {
+            let print = || {
+                let done = download.received.load(Ordering::Relaxed);
+                print!("\rDownloaded {}/{} bytes ({:.1}%)", done, total, (done as f64 / total.max(1) as f64) * 100.0);
+                std::io::stdout().flush()
+            };
+            while download.finished.load(Ordering::Relaxed) < segments {
+                print()?;
+                async_std::task::sleep(PROGRESS_INTERVAL).await;
+            }
+            print()?;
+            println!();
+            Ok::<(), io::Error>(())
+        };
+        let ((first, others), shown) = future::zip(future::zip(first, others), show_progress).await;
+        first?;
+        for result in others {
+            result?;
         }
-        let md5_hex = md5_line[4..].trim();
+        shown?;
 
-        let our_hex = format!("{:x}", context.finalize());
-        if our_hex == md5_hex {
-            println!("MD5 OK: {}", md5_hex);
-        } else {
-            println!("MD5 MISMATCH! server: {} local: {}", md5_hex, our_hex);
+        let mut checksum = algorithm.hasher();
+        hash_prefix(&mut File::open(out_path)?, total, checksum.as_mut())?;
+        report_hash(checksum.as_ref(), algorithm, &download.digest)
+    }
+}
+
+/// One file fetched as byte ranges over several connections. Every range
+/// must report the same size, and the same whole-file digest from servers
+/// that cannot leave it out, or the file changed on the server while we
+/// fetched it.
+struct SegmentedDownload {
+    name: String,
+    path: PathBuf, // preallocated at full size
+    hash: Option<Algorithm>,
+    total: u64,
+    algorithm: Algorithm,
+    digest: String,
+    received: AtomicU64, // for the merged progress line
+    finished: AtomicUsize, // segments done, successfully or not
+}
+
+impl SegmentedDownload {
+    /// GET `len` bytes from `start` on `session` and write them at the
+    /// same offset of the local file.
+    async fn fetch(&self, session: &mut Session, start: u64, len: u64) -> io::Result<()> {
+        let changed = || io::Error::new(io::ErrorKind::InvalidData, format!("{} changed on the server during the download", self.name));
+        // The probe brought the digest; spare the server hashing the whole
+        // file again for every range where it lets us
+        let trailer = !session.supports(Capability::HashNone);
+        let header = request_file(session, &self.name, start, Some(len), self.hash, trailer, None).await?;
+        if let Some(err) = header.trim_end().strip_prefix("ERR ") {
+            return Err(io::Error::other(format!("server error: {}", err)));
+        }
+        let FileHeader { size, offset, total, .. } = parse_file_header(&header)?;
+        if total != self.total || offset != start || size != len {
+            return Err(changed());
         }
 
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut remaining = len;
+        let mut buf = vec![0u8; 64 * 1024];
+        while remaining > 0 {
+            let to_read = std::cmp::min(buf.len() as u64, remaining) as usize;
+            let n = session.read_some(&mut buf[..to_read]).await?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    format!("server closed while sending bytes {}-{}", start, start + len)));
+            }
+            file.write_all(&buf[..n])?;
+            remaining -= n as u64;
+            self.received.fetch_add(n as u64, Ordering::Relaxed);
+            session.throttle(n).await;
+        }
+
+        if !trailer {
+            // Only the newline after the body; the whole file is checked at the end
+            session.read_exact(&mut [0u8; 1]).await?;
+            return Ok(());
+        }
+        let (algorithm, digest) = read_trailer(session).await?;
+        if algorithm != self.algorithm || !digest.eq_ignore_ascii_case(&self.digest) {
+            return Err(changed());
+        }
         Ok(())
     }
 }
+
+/// Errors a reconnect may get past, as opposed to ones from the server
+/// or the local disk that would only happen again.
+pub(crate) fn is_dropped_connection(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(e.kind(), UnexpectedEof | ConnectionReset | ConnectionAborted | ConnectionRefused | BrokenPipe
+        | NotConnected | TimedOut | HostUnreachable | NetworkUnreachable | NetworkDown)
+}
+
+/// A transfer whose digest differs from the server's.
+#[derive(Debug)]
+struct HashMismatch {
+    algorithm: Algorithm,
+    server: String,
+    local: String,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} mismatch: server {}, local {}", self.algorithm, self.server, self.local)
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+fn is_hash_mismatch(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<HashMismatch>())
+}
+
+/// Compare our digest of a download with the server's trailer and print
+/// the result. A mismatch is an error, so the exit status shows it.
+fn report_hash(checksum: &dyn Checksum, algorithm: Algorithm, server_hex: &str) -> io::Result<()> {
+    if algorithm != checksum.algorithm() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("server hashed with {}, expected {}", algorithm, checksum.algorithm())));
+    }
+
+    let label = algorithm.name().to_uppercase();
+    let our_hex = checksum.hex_digest();
+    if our_hex.eq_ignore_ascii_case(server_hex) {
+        println!("{} OK: {}", label, server_hex);
+    } else {
+        println!("{} MISMATCH! server: {} local: {}", label, server_hex, our_hex);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, HashMismatch { algorithm, server: server_hex.to_string(), local: our_hex }));
+    }
+    Ok(())
+}
+
+/// Blocking `Read` over chunks handed across from the async session, for
+/// the archive unpacker thread. A closed channel reads as end of file.
+struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl std::io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// TLS server name from the host part of `host:port` (or `[v6]:port`).
+fn server_name(addr: &str) -> io::Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Compare a listed entry with the local copy under `out_dir`, if any.
+fn local_status(out_dir: &Path, entry: &Entry) -> &'static str {
+    if entry.kind == EntryKind::Dir {
+        return "";
+    }
+    let Ok(metadata) = local_path(out_dir, &entry.name).and_then(std::fs::metadata) else {
+        return "";
+    };
+    let local_mtime = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    if metadata.len() < entry.size {
+        "  [local: partial]"
+    } else if metadata.len() != entry.size || local_mtime < entry.mtime {
+        "  [local: stale]"
+    } else {
+        "  [local: up to date]"
+    }
+}
+
+/// What `sync` has to do about a local file that exists as `metadata`
+/// for the remote `entry`.
+fn compare_local(path: &Path, metadata: &std::fs::Metadata, entry: &Entry) -> io::Result<Option<SyncStep>> {
+    if metadata.len() != entry.size {
+        return Ok(Some(SyncStep::Changed(entry.clone())));
+    }
+    let mtime = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    if mtime == entry.mtime {
+        return Ok(None);
+    }
+    let Some(md5) = &entry.md5 else {
+        return Ok(Some(SyncStep::Changed(entry.clone())));
+    };
+    let mut checksum = Algorithm::Md5.hasher();
+    hash_prefix(&mut File::open(path)?, metadata.len(), checksum.as_mut())?;
+    Ok(Some(match checksum.hex_digest().eq_ignore_ascii_case(md5) {
+        true => SyncStep::Touch(entry.clone()),
+        false => SyncStep::Changed(entry.clone()),
+    }))
+}
+
+/// Collect local paths under `dir` that the server does not list with the
+/// same kind, as remote-style names. Directories the server also has are
+/// descended into; the others go as a whole.
+fn local_extras(out_dir: &Path, dir: &Path, remote: &HashMap<&str, EntryKind>, out: &mut Vec<String>) -> io::Result<()> {
+    let mut entries = match std::fs::read_dir(dir) {
+        // Nothing mirrored yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        read => read?.collect::<io::Result<Vec<_>>>()?,
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let Some(name) = path.strip_prefix(out_dir).ok().and_then(Path::to_str) else {
+            continue;
+        };
+        let name = name.replace(std::path::MAIN_SEPARATOR, "/");
+        let is_dir = entry.file_type()?.is_dir();
+        match remote.get(name.as_str()) {
+            None => out.push(name),
+            Some(EntryKind::Dir) if is_dir => local_extras(out_dir, &path, remote, out)?,
+            // Kind mismatches are already replaced by the plan
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn set_mtime(path: &Path, mtime: u64) -> io::Result<()> {
+    File::options().write(true).open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+}
+
+/// An MGET argument matching `name` alone. Names without glob characters
+/// are taken literally; in the others every glob character is escaped.
+fn literal_pattern(name: &str) -> String {
+    if !name.contains(['*', '?', '[', '{']) {
+        return name.to_string();
+    }
+    let mut out = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '{' | '}' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Map a `/`-separated remote path to a location under `out_dir`,
+/// refusing anything that would land outside it.
+fn local_path(out_dir: &Path, remote: &str) -> io::Result<PathBuf> {
+    let mut path = out_dir.to_path_buf();
+    for part in remote.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("refusing path outside output dir: {}", remote))),
+            part => path.push(part),
+        }
+    }
+    if path == out_dir {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid file name: {}", remote)));
+    }
+    Ok(path)
+}
+
+/// Send a LIST command and collect the response lines up to the `.` marker.
+async fn list_lines(session: &mut Session, cmd: &str) -> io::Result<Vec<String>> {
+    session.send(cmd).await?;
+
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let n = session.read_line(&mut line).await?;
+        if n == 0 { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed")); }
+        let line = line.trim_end();
+        if line == "." { break; }
+        if let Some(err) = line.strip_prefix("ERR ") {
+            return Err(io::Error::other(format!("server error: {}", err)));
+        }
+        lines.push(line.to_string());
+    }
+    Ok(lines)
+}
+
+/// Send `GET name [offset [length]] [HASH=alg] [COMPRESS=codec]` and return
+/// the response header line. Without `trailer` it asks for `HASH=none`.
+async fn request_file(session: &mut Session, filename: &str, offset: u64, length: Option<u64>, hash: Option<Algorithm>, trailer: bool, compression: Option<Compression>) -> io::Result<String> {
+    let mut cmd = format!("GET {}", escape_name(filename));
+    match length {
+        Some(length) => cmd.push_str(&format!(" {} {}", offset, length)),
+        None if offset > 0 => cmd.push_str(&format!(" {}", offset)),
+        None => {}
+    }
+    if !trailer {
+        cmd.push_str(" HASH=none");
+    } else if let Some(algorithm) = hash {
+        cmd.push_str(&format!(" HASH={}", algorithm));
+    }
+    if let Some(compression) = compression {
+        cmd.push_str(&format!(" COMPRESS={}", compression));
+    }
+    cmd.push('\n');
+    session.send(&cmd).await?;
+
+    let mut header = String::new();
+    session.read_line(&mut header).await?;
+    Ok(header)
+}
+
+/// A `FILE` response header. Sizes are of the uncompressed content, apart
+/// from `compressed`, the length of a compressed body on the wire.
+struct FileHeader {
+    size: u64,
+    offset: u64,
+    total: u64,
+    compression: Option<Compression>,
+    compressed: Option<u64>,
+    digest: Option<(Algorithm, String)>, // whole-file digest, if the server had it to hand
+}
+
+/// Parse `FILE <size>` or the ranged `FILE <size> <offset> <total>`, either
+/// optionally followed by `COMPRESS=<codec> COMPRESSED=<len>` and
+/// `DIGEST=<alg>:<hex>`.
+fn parse_file_header(header: &str) -> io::Result<FileHeader> {
+    let Some(fields) = header.strip_prefix("FILE ") else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected header: {}", header)));
+    };
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut sizes = Vec::new();
+    let mut compression = None;
+    let mut compressed = None;
+    let mut digest = None;
+    for field in fields.split_whitespace() {
+        if let Some(name) = field.strip_prefix("COMPRESS=") {
+            compression = Some(name.parse::<Compression>().map_err(invalid)?);
+        } else if let Some(len) = field.strip_prefix("COMPRESSED=") {
+            compressed = Some(len.parse::<u64>().map_err(|e| invalid(e.to_string()))?);
+        } else if let Some(value) = field.strip_prefix("DIGEST=") {
+            digest = Some(parse_digest(value).map_err(invalid)?);
+        } else {
+            sizes.push(field.parse::<u64>().map_err(|e| invalid(e.to_string()))?);
+        }
+    }
+    let (size, offset, total) = match sizes[..] {
+        [size] => (size, 0, size),
+        [size, offset, total] => (size, offset, total),
+        _ => return Err(invalid(format!("malformed header: {}", header))),
+    };
+    Ok(FileHeader { size, offset, total, compression, compressed, digest })
+}
+
+/// The `<alg>:<hex>` of a `DIGEST=` header field.
+fn parse_digest(value: &str) -> Result<(Algorithm, String), String> {
+    let (algorithm, hex) = value.split_once(':').ok_or_else(|| format!("malformed digest: {}", value))?;
+    Ok((algorithm.parse()?, hex.to_string()))
+}
+
+/// Read one `DATA <len>` chunk of a body sent without a length up front.
+/// Returns None at the closing `DATA 0`.
+async fn read_data_chunk(session: &mut Session) -> io::Result<Option<Vec<u8>>> {
+    let mut line = String::new();
+    session.read_line(&mut line).await?;
+    let len = match line.trim_end().strip_prefix("DATA ").map(str::parse::<usize>) {
+        Some(Ok(len)) => len,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected DATA, got: {}", line.trim_end()))),
+    };
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut chunk = vec![0u8; len];
+    session.read_exact(&mut chunk).await?;
+    session.throttle(len).await;
+    Ok(Some(chunk))
+}
+
+/// Read a `size`-byte file body into `out`, hashing it on the way. Local
+/// write errors are returned in the inner result after the whole body has
+/// been consumed, so the session stays in sync.
+async fn read_body(session: &mut Session, out: &mut dyn Write, size: u64, checksum: &mut dyn Checksum) -> io::Result<io::Result<()>> {
+    let mut remaining = size;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut write_result = Ok(());
+    while remaining > 0 {
+        let to_read = std::cmp::min(buf.len() as u64, remaining) as usize;
+        let n = session.read_some(&mut buf[..to_read]).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                format!("server closed while sending file (got {}/{} bytes)", size - remaining, size)));
+        }
+        checksum.update(&buf[..n]);
+        if write_result.is_ok() {
+            write_result = out.write_all(&buf[..n]);
+        }
+        remaining -= n as u64;
+        session.throttle(n).await;
+    }
+    Ok(write_result)
+}
+
+/// Read the newline after a file body and the `MD5`/`HASH` line that follows.
+async fn read_trailer(session: &mut Session) -> io::Result<(Algorithm, String)> {
+    let mut nl = [0u8; 1];
+    session.read_exact(&mut nl).await?;
+    let mut hash_line = String::new();
+    session.read_line(&mut hash_line).await?;
+    parse_hash_trailer(&hash_line)
+}
+
+/// Parse the trailer after a file body: legacy `MD5 <hex>` or `HASH <alg> <hex>`.
+fn parse_hash_trailer(line: &str) -> io::Result<(Algorithm, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("expected MD5 or HASH, got: {}", line));
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["MD5", hex] => Ok((Algorithm::Md5, hex.to_string())),
+        ["HASH", algorithm, hex] => Ok((algorithm.parse().map_err(|_| invalid())?, hex.to_string())),
+        _ => Err(invalid()),
+    }
+}
+
+/// Hash the first `len` bytes of an existing local file, leaving it positioned at `len`.
+fn hash_prefix(file: &mut File, len: u64, checksum: &mut dyn Checksum) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let to_read = std::cmp::min(buf.len() as u64, left) as usize;
+        let n = file.read(&mut buf[..to_read])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "local file shrank while resuming"));
+        }
+        checksum.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(())
+}
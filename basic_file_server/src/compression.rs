@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Streaming compressor or decompressor. Output collects in memory until
+/// taken, so callers decide when and how it goes on the wire or to disk.
+pub trait Codec: Send {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Everything produced so far; the codec stays usable.
+    fn take_output(&mut self) -> Vec<u8>;
+
+    /// Flush the end of the stream and return the remaining output.
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+    /// Name used on the wire, e.g. in `COMPRESS=zstd`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    pub fn compressor(self) -> io::Result<Box<dyn Codec>> {
+        Ok(match self {
+            Compression::Zstd => Box::new(ZstdEncoder(zstd::stream::write::Encoder::new(Vec::new(), 0)?)),
+            Compression::Gzip => Box::new(GzipEncoder(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()))),
+        })
+    }
+
+    pub fn decompressor(self) -> io::Result<Box<dyn Codec>> {
+        Ok(match self {
+            Compression::Zstd => Box::new(ZstdDecoder(zstd::stream::write::Decoder::new(Vec::new())?)),
+            Compression::Gzip => Box::new(GzipDecoder(flate2::write::GzDecoder::new(Vec::new()))),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compression::ALL.into_iter()
+            .find(|compression| compression.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unsupported compression: {}", s))
+    }
+}
+
+struct ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>);
+
+impl Codec for ZstdEncoder {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.0.get_mut())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
+
+struct ZstdDecoder(zstd::stream::write::Decoder<'static, Vec<u8>>);
+
+impl Codec for ZstdDecoder {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.0.get_mut())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<Vec<u8>> {
+        self.0.flush()?;
+        Ok(self.0.into_inner())
+    }
+}
+
+struct GzipEncoder(flate2::write::GzEncoder<Vec<u8>>);
+
+impl Codec for GzipEncoder {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.0.get_mut())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
+
+struct GzipDecoder(flate2::write::GzDecoder<Vec<u8>>);
+
+impl Codec for GzipDecoder {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.0.get_mut())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
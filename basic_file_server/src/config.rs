@@ -0,0 +1,131 @@
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::logging::{LogFormat, LogOptions};
+use crate::ratelimit::Rate;
+
+/// The file behind `server --config`. Every key is optional and a flag
+/// given on the command line wins over it. Relative paths are taken from
+/// the directory holding the file, so a deployment can keep its config,
+/// certificates and users file together.
+///
+/// ```toml
+/// listen = "0.0.0.0:4000"
+/// mount = "/srv/files"
+/// workers = 4
+///
+/// [tls]
+/// cert = "certs/server.pem"
+/// key = "certs/server.key"
+///
+/// [auth]
+/// tokens = ["s3cret"]
+/// users_file = "users.txt"
+/// users = ["alice:pbkdf2-sha256:..."]
+///
+/// [limits]
+/// rate = "10MiB/s"
+/// idle_timeout = 300
+///
+/// [log]
+/// level = "info"
+/// format = "json"
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Option<SocketAddr>,
+    pub mount: Option<PathBuf>,
+    pub workers: Option<usize>,
+    pub zero_copy: Option<bool>,
+    pub metrics_addr: Option<SocketAddr>,
+    /// seconds
+    pub drain_timeout: Option<u64>,
+    pub index: Option<bool>,
+    /// seconds
+    pub index_interval: Option<u64>,
+    pub tls: Option<TlsSection>,
+    pub auth: AuthSection,
+    pub limits: LimitsSection,
+    pub log: LogSection,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub tokens: Vec<String>,
+    pub users_file: Option<PathBuf>,
+    /// entries in the users-file format, for deployments that keep them inline
+    pub users: Vec<String>,
+}
+
+/// Timeouts are in seconds, and 0 disables them as on the command line.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    #[serde(deserialize_with = "parsed")]
+    pub rate: Option<Rate>,
+    #[serde(deserialize_with = "parsed")]
+    pub global_rate: Option<Rate>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub idle_timeout: Option<u64>,
+    pub header_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    pub level: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub format: Option<LogFormat>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot read {}: {}", path.display(), e)))?;
+        let mut config: ServerConfig = toml::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        let paths = [self.mount.as_mut(), self.auth.users_file.as_mut()].into_iter().flatten();
+        let tls = self.tls.iter_mut().flat_map(|tls| [&mut tls.cert, &mut tls.key]);
+        for path in paths.chain(tls) {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+    }
+
+    pub fn log_options(&self) -> LogOptions {
+        LogOptions { log_level: self.log.level.clone(), log_format: self.log.format }
+    }
+}
+
+/// Read an optional value through its `FromStr`, as the command line does.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::debug;
+
+use crate::checksum::Algorithm;
+use crate::protocol::{escape_name, unescape_name};
+
+/// Sidecar file in the root of the mount. Names starting with it are never
+/// listed, served or accepted as uploads.
+pub const INDEX_FILE: &str = ".bfs-index";
+const HEADER: &str = "# bfs checksum index 1";
+
+/// Whole-file digests keyed by canonical path and algorithm. An entry is
+/// only trusted while the file's size and mtime match what was hashed.
+/// Completed GETs and PUTs fill it in; a persistent index is also filled
+/// by `scan` and saved to `INDEX_FILE`, one line per digest:
+/// `<algorithm> <hex> <size> <mtime ns|-> <escaped path>`.
+#[derive(Debug, Default)]
+pub struct ChecksumIndex {
+    entries: HashMap<(PathBuf, Algorithm), Digest>,
+    dirty: bool, // changed since the last snapshot
+}
+
+#[derive(Debug)]
+struct Digest {
+    size: u64,
+    mtime: Option<SystemTime>,
+    hex: String,
+}
+
+/// What one `scan` found and did.
+#[derive(Debug, Default)]
+pub struct ScanStats {
+    pub files: usize,
+    pub hashed: usize,
+    pub removed: usize,
+}
+
+impl ChecksumIndex {
+    pub fn get(&self, path: &Path, metadata: &Metadata, algorithm: Algorithm) -> Option<&str> {
+        let digest = self.entries.get(&(path.to_path_buf(), algorithm))?;
+        (digest.size == metadata.len() && digest.mtime == metadata.modified().ok()).then_some(digest.hex.as_str())
+    }
+
+    pub fn insert(&mut self, path: PathBuf, metadata: &Metadata, algorithm: Algorithm, hex: String) {
+        let digest = Digest { size: metadata.len(), mtime: metadata.modified().ok(), hex };
+        self.entries.insert((path, algorithm), digest);
+        self.dirty = true;
+    }
+
+    /// Add the digests saved in the sidecar of `root`, which must be
+    /// canonical. No sidecar yet is an empty index. Returns how many were
+    /// read.
+    pub fn load(&mut self, root: &Path) -> io::Result<usize> {
+        let path = root.join(INDEX_FILE);
+        let file = match File::open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            file => file?,
+        };
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a checksum index", path.display())));
+        }
+        let mut loaded = 0;
+        for line in lines {
+            let line = line?;
+            let Some((name, algorithm, digest)) = parse_line(&line) else {
+                debug!(line, "skipping malformed index line");
+                continue;
+            };
+            self.entries.insert((root.join(name), algorithm), digest);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// The sidecar contents for the files under `root`, if anything changed
+    /// since the last snapshot. Written out with `save` once the lock on the
+    /// index is released.
+    pub fn snapshot(&mut self, root: &Path) -> Option<String> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        let mut out = format!("{}\n", HEADER);
+        for ((path, algorithm), digest) in &self.entries {
+            let Some(name) = path.strip_prefix(root).ok().and_then(Path::to_str) else {
+                continue;
+            };
+            let mtime = digest.mtime
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(|| "-".to_string(), |d| d.as_nanos().to_string());
+            let name = escape_name(&name.replace(std::path::MAIN_SEPARATOR, "/"));
+            out.push_str(&format!("{} {} {} {} {}\n", algorithm, digest.hex, digest.size, mtime, name));
+        }
+        Some(out)
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, Algorithm, Digest)> {
+    let [algorithm, hex, size, mtime, name] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let mtime = match mtime {
+        "-" => None,
+        nanos => Some(UNIX_EPOCH + Duration::from_nanos(nanos.parse().ok()?)),
+    };
+    let digest = Digest { size: size.parse().ok()?, mtime, hex: hex.to_string() };
+    Some((unescape_name(name), algorithm.parse().ok()?, digest))
+}
+
+/// Replace the sidecar of `root` with `contents`. The new file is renamed
+/// over the old one, so a crash never leaves half an index behind.
+pub fn save(root: &Path, contents: &str) -> io::Result<()> {
+    let tmp = root.join(format!("{}.tmp", INDEX_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, root.join(INDEX_FILE))
+}
+
+/// Bring `index` up to date with the files under `root`: hash each file
+/// without a current MD5 and forget the ones that are gone. Files are
+/// hashed without holding the lock, so transfers only ever wait for an
+/// insert. Names for which `hidden` is true are not part of the mount.
+pub fn scan(index: &RwLock<ChecksumIndex>, root: &Path, hidden: fn(&str) -> bool) -> io::Result<ScanStats> {
+    let mut files = Vec::new();
+    walk(root, hidden, &mut files)?;
+    let mut stats = ScanStats { files: files.len(), ..ScanStats::default() };
+    for path in &files {
+        // Gone since the walk, or already current
+        let Ok(metadata) = std::fs::metadata(path) else {
+            continue;
+        };
+        if index.read().unwrap().get(path, &metadata, Algorithm::Md5).is_some() {
+            continue;
+        }
+        let hex = match hash_file(path, Algorithm::Md5) {
+            Ok(hex) => hex,
+            Err(e) => {
+                debug!(error = %e, path = %path.display(), "cannot hash file");
+                continue;
+            }
+        };
+        // A file written to while we read it gets another go next scan
+        let unchanged = std::fs::metadata(path)
+            .is_ok_and(|after| after.len() == metadata.len() && after.modified().ok() == metadata.modified().ok());
+        if unchanged {
+            index.write().unwrap().insert(path.clone(), &metadata, Algorithm::Md5, hex);
+            stats.hashed += 1;
+        }
+    }
+
+    let present = files.iter().collect::<HashSet<_>>();
+    let mut index = index.write().unwrap();
+    let before = index.entries.len();
+    index.entries.retain(|(path, _), _| !path.starts_with(root) || present.contains(path));
+    stats.removed = before - index.entries.len();
+    if stats.removed > 0 {
+        index.dirty = true;
+    }
+    Ok(stats)
+}
+
+/// Regular files under `dir`, not following symlinks; a link to a file in
+/// the mount is served from, and indexed at, its target.
+fn walk(dir: &Path, hidden: fn(&str) -> bool, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_none_or(hidden) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), hidden, out)?;
+        } else if file_type.is_file() {
+            out.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path, algorithm: Algorithm) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut checksum = algorithm.hasher();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(checksum.hex_digest());
+        }
+        checksum.update(&buf[..n]);
+    }
+}
@@ -1,5 +1,16 @@
 pub mod server;
+pub mod tls;
+pub mod auth;
+pub mod checksum;
+pub mod compression;
 pub mod client;
+pub mod protocol;
+pub mod ratelimit;
+pub mod logging;
+pub mod metrics;
+pub mod config;
+pub mod index;
+pub mod shell;
 
 
 pub use server::Server;
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use clap::Args;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub const ALL: [LogFormat; 2] = [LogFormat::Text, LogFormat::Json];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogFormat::ALL.into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unsupported log format: {}", s))
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct LogOptions {
+    /// log filter: a level (error, warn, info, debug, trace) or tracing
+    /// directives such as `basic_file_server::server=debug`; RUST_LOG wins
+    /// [default: info]
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// log line format: text or json [default: text]
+    #[arg(long, global = true)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl LogOptions {
+    /// These options, with anything left unset taken from `fallback`.
+    pub fn or(self, fallback: LogOptions) -> LogOptions {
+        LogOptions {
+            log_level: self.log_level.or(fallback.log_level),
+            log_format: self.log_format.or(fallback.log_format),
+        }
+    }
+}
+
+/// Install the global subscriber. Logs go to stderr so they never mix with
+/// listings and progress the client prints on stdout.
+pub fn init(options: &LogOptions) -> io::Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => EnvFilter::try_new(directives),
+        _ => EnvFilter::try_new(options.log_level.as_deref().unwrap_or("info")),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid log filter: {}", e)))?;
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(io::stderr);
+    let result = match options.log_format.unwrap_or_default() {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    result.map_err(io::Error::other)
+}
@@ -1,7 +1,9 @@
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
 
 use basic_file_server::client::ClientCli;
+use basic_file_server::config::ServerConfig;
+use basic_file_server::logging::{self, LogOptions};
+use basic_file_server::server::ServerCli;
 use basic_file_server::Server;
 
 #[derive(Parser)]
@@ -9,24 +11,40 @@ use basic_file_server::Server;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[command(flatten)]
+    log: LogOptions,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Server { addr: String, mount: PathBuf },
+    Server { #[command(flatten)] opts: ServerCli },
     Client { #[command(flatten)] opts: ClientCli },
+    /// Print a users-file line for --auth-users; prompts for the password, or reads it from stdin
+    HashPassword { user: String },
 }
 
 fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
+    // The server's config file can set the log options, so read it first
+    let config = match &cli.command {
+        Commands::Server { opts: ServerCli { config: Some(path), .. } } => ServerConfig::load(path)?,
+        _ => ServerConfig::default(),
+    };
+    logging::init(&cli.log.or(config.log_options()))?;
     match cli.command {
-        Commands::Server { addr, mount } => {
-            let mut server = Server::new(&addr, mount);
+        Commands::Server { opts } => {
+            let mut server = Server::from_cli(opts, config)?;
             server.run()
         }
         Commands::Client { opts } => {
             // Start async-std runtime for client
-            async_std::task::block_on(basic_file_server::client::Client::run_cli(opts)).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            async_std::task::block_on(basic_file_server::client::Client::run_cli(opts)).map_err(std::io::Error::other)
+        }
+        Commands::HashPassword { user } => {
+            let password = basic_file_server::auth::read_password("Password: ")?;
+            println!("{}", basic_file_server::auth::hash_password(&user, &password));
+            Ok(())
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+/// Commands counted by name; anything else is counted as `other`.
+const COMMANDS: [&str; 10] = ["HELLO", "AUTH", "LIST", "STAT", "GET", "MGET", "GETDIR", "PUT", "MD5", "HASH"];
+
+/// Upper bounds, in seconds, of the transfer duration histogram buckets.
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.025, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 30.0, 120.0, 600.0];
+
+/// Server counters, updated lock-free on the hot paths and rendered in the
+/// Prometheus text format on scrape.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    bytes_sent: AtomicU64,
+    files_served: AtomicU64,
+    archives_served: AtomicU64,
+    commands: [AtomicU64; COMMANDS.len() + 1],
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    durations: Histogram,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn command(&self, name: &str) {
+        let index = COMMANDS.iter().position(|command| *command == name).unwrap_or(COMMANDS.len());
+        self.commands[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an `ERR` response by the reason label of its message.
+    pub fn error(&self, message: &str) {
+        *self.errors.lock().unwrap().entry(error_reason(message)).or_default() += 1;
+    }
+
+    pub fn file_served(&self, duration: Duration) {
+        self.files_served.fetch_add(1, Ordering::Relaxed);
+        self.durations.observe(duration);
+    }
+
+    pub fn archive_served(&self, duration: Duration) {
+        self.archives_served.fetch_add(1, Ordering::Relaxed);
+        self.durations.observe(duration);
+    }
+
+    /// Everything in the Prometheus text exposition format. The active
+    /// connection count lives with the connection limits, so it is passed in.
+    pub fn render(&self, active_connections: usize) -> String {
+        let mut out = String::new();
+        gauge(&mut out, "bfs_active_connections", "Open client connections.", active_connections as u64);
+        counter(&mut out, "bfs_connections_total", "Client connections accepted.", &self.connections);
+        counter(&mut out, "bfs_bytes_sent_total", "Bytes written to client sockets.", &self.bytes_sent);
+        counter(&mut out, "bfs_files_served_total", "Files sent completely by GET or MGET.", &self.files_served);
+        counter(&mut out, "bfs_archives_served_total", "Directory archives sent completely by GETDIR.", &self.archives_served);
+
+        header(&mut out, "bfs_commands_total", "Commands received, by name.", "counter");
+        for (name, count) in COMMANDS.iter().chain(["other"].iter()).zip(&self.commands) {
+            let _ = writeln!(out, "bfs_commands_total{{command=\"{}\"}} {}", name, count.load(Ordering::Relaxed));
+        }
+
+        header(&mut out, "bfs_errors_total", "ERR responses sent, by reason.", "counter");
+        for (reason, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "bfs_errors_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        self.durations.render(&mut out, "bfs_transfer_duration_seconds", "Time from sending a file or archive header to its trailer.");
+        out
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { buckets: Default::default(), count: AtomicU64::new(0), sum_micros: AtomicU64::new(0) }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Map the text of an `ERR` line to a label with few enough values for
+/// Prometheus. Per-file MGET errors put the name first, so those reasons
+/// are matched at the end of the message.
+fn error_reason(message: &str) -> &'static str {
+    const SUFFIXES: [(&str, &str); 6] = [
+        ("file not found", "not_found"),
+        ("not negotiated", "not_negotiated"),
+        ("access denied", "access_denied"),
+        ("invalid path", "invalid_path"),
+        ("no match", "no_match"),
+        ("read error", "read_error"),
+    ];
+    const PREFIXES: [(&str, &str); 13] = [
+        ("authentication required", "auth_required"),
+        ("authentication failed", "auth_failed"),
+        ("usage:", "usage"),
+        ("unknown command", "unknown_command"),
+        ("not a directory", "not_a_directory"),
+        ("more than", "too_many_matches"),
+        ("busy", "busy"),
+        ("upload", "upload"),
+        ("no upload", "upload"),
+        ("malformed hash", "upload"),
+        ("invalid", "invalid_argument"),
+        ("unknown", "invalid_argument"),
+        ("missing", "invalid_argument"),
+    ];
+    let message = message.trim_end();
+    if let Some((_, reason)) = SUFFIXES.iter().find(|(suffix, _)| message.ends_with(suffix)) {
+        return reason;
+    }
+    if message.contains(" mismatch") {
+        return "checksum_mismatch";
+    }
+    PREFIXES.iter()
+        .find(|(prefix, _)| message.starts_with(prefix))
+        .map_or("other", |(_, reason)| reason)
+}
+
+/// Serve `render()` at `/metrics` over plain HTTP on a thread of its own,
+/// one scrape at a time.
+pub fn serve(addr: SocketAddr, render: impl Fn() -> String + Send + 'static) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(%addr, "metrics listening");
+    std::thread::Builder::new().name("metrics".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| answer_scrape(stream, &render));
+            if let Err(e) = result {
+                debug!(error = %e, "metrics request failed");
+            }
+        }
+        warn!("metrics listener stopped");
+    })?;
+    Ok(())
+}
+
+fn answer_scrape(stream: TcpStream, render: &impl Fn() -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers; nothing in them changes the answer
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::checksum::Algorithm;
+use crate::compression::Compression;
+
+/// Escape a path for the line protocol. Backslash and line breaks are
+/// backslash-escaped, and so is every other whitespace or control
+/// character, as `\u{hex}`, since lines are split on Unicode whitespace. A
+/// leading `.` becomes `\.` so no entry can ever be mistaken for the `.`
+/// end-of-list marker.
+pub fn escape_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ' ' => out.push_str("\\s"),
+            '.' if i == 0 => out.push_str("\\."),
+            c if c.is_whitespace() || c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Inverse of `escape_name`. Unknown escapes keep the escaped character.
+pub fn unescape_name(escaped: &str) -> String {
+    let mut out = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('s') => out.push(' '),
+            Some('u') => match unescape_code_point(chars.as_str()) {
+                Some((c, len)) => {
+                    out.push(c);
+                    chars = chars.as_str()[len..].chars();
+                }
+                None => out.push('u'),
+            },
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// The character of a `{hex}` escape at the start of `s`, and how many
+/// bytes it takes up.
+fn unescape_code_point(s: &str) -> Option<(char, usize)> {
+    let end = s.find('}')?;
+    let hex = s.strip_prefix('{')?.get(..end - 1)?;
+    let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)?;
+    Some((c, end + 1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl EntryKind {
+    fn as_char(self) -> char {
+        match self {
+            EntryKind::File => 'f',
+            EntryKind::Dir => 'd',
+            EntryKind::Symlink => 'l',
+        }
+    }
+}
+
+/// One line of a `LIST -l` response:
+/// `<f|d|l> <size> <mtime> <md5|-> <escaped path>`
+/// with mtime in seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: u64,
+    pub md5: Option<String>,
+    pub name: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {} {}",
+            self.kind.as_char(),
+            self.size,
+            self.mtime,
+            self.md5.as_deref().unwrap_or("-"),
+            escape_name(&self.name))
+    }
+}
+
+impl FromStr for Entry {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [kind, size, mtime, md5, name] = fields[..] else {
+            return Err(format!("malformed entry: {}", line));
+        };
+        let kind = match kind {
+            "f" => EntryKind::File,
+            "d" => EntryKind::Dir,
+            "l" => EntryKind::Symlink,
+            other => return Err(format!("unknown entry type: {}", other)),
+        };
+        Ok(Entry {
+            kind,
+            size: size.parse().map_err(|e| format!("bad size in {:?}: {}", line, e))?,
+            mtime: mtime.parse().map_err(|e| format!("bad mtime in {:?}: {}", line, e))?,
+            md5: (md5 != "-").then(|| md5.to_string()),
+            name: unescape_name(name),
+        })
+    }
+}
+
+/// Archive formats `GETDIR` can stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 2] = [ArchiveFormat::Tar, ArchiveFormat::TarZst];
+
+    /// Name used on the wire, e.g. in `GETDIR logs tar.zst`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ArchiveFormat::ALL.into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unsupported archive format: {}", s))
+    }
+}
+
+/// Protocol version spoken after a `HELLO` exchange. A peer that never
+/// sends `HELLO` speaks version 0: the commands that predate it, nothing
+/// negotiated.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features a peer announces in `HELLO`. Each side uses only those
+/// both announced. The server lists `auth` only when it requires `AUTH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Auth,
+    /// `GET` with an offset and length
+    Range,
+    /// `LIST -l`
+    ListLong,
+    Mget,
+    Getdir,
+    Put,
+    /// `COMPRESS=zstd` and `tar.zst` archives
+    Zstd,
+    Gzip,
+    Sha256,
+    Blake3,
+    /// `STAT <name>`
+    Stat,
+    /// `GET ... HASH=none`, without the hash trailer
+    HashNone,
+    /// `DIGEST=<alg>:<hex>` in `FILE` and `MFILE` headers, when the server
+    /// already knows the digest
+    Digest,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 13] = [
+        Capability::Auth, Capability::Range, Capability::ListLong, Capability::Mget, Capability::Getdir,
+        Capability::Put, Capability::Zstd, Capability::Gzip, Capability::Sha256, Capability::Blake3,
+        Capability::Stat, Capability::HashNone, Capability::Digest,
+    ];
+
+    /// What every server had before `HELLO`, so what one that does not
+    /// answer it is assumed to support.
+    pub const LEGACY: [Capability; 10] = [
+        Capability::Auth, Capability::Range, Capability::ListLong, Capability::Mget, Capability::Getdir,
+        Capability::Put, Capability::Zstd, Capability::Gzip, Capability::Sha256, Capability::Blake3,
+    ];
+
+    /// Name used on the wire, e.g. in `HELLO 1 range mget zstd`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Auth => "auth",
+            Capability::Range => "range",
+            Capability::ListLong => "list-l",
+            Capability::Mget => "mget",
+            Capability::Getdir => "getdir",
+            Capability::Put => "put",
+            Capability::Zstd => "zstd",
+            Capability::Gzip => "gzip",
+            Capability::Sha256 => "sha256",
+            Capability::Blake3 => "blake3",
+            Capability::Stat => "stat",
+            Capability::HashNone => "hash-none",
+            Capability::Digest => "digest",
+        }
+    }
+
+    /// What `HASH=<algorithm>` needs; MD5 was always there.
+    pub fn for_hash(algorithm: Algorithm) -> Option<Capability> {
+        match algorithm {
+            Algorithm::Md5 => None,
+            Algorithm::Sha256 => Some(Capability::Sha256),
+            Algorithm::Blake3 => Some(Capability::Blake3),
+        }
+    }
+
+    pub fn for_compression(compression: Compression) -> Capability {
+        match compression {
+            Compression::Zstd => Capability::Zstd,
+            Compression::Gzip => Capability::Gzip,
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL.into_iter()
+            .find(|capability| capability.name() == s)
+            .ok_or_else(|| format!("unknown capability: {}", s))
+    }
+}
+
+/// A set of capabilities. Displays as the space-separated names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub fn all() -> Self {
+        Capability::ALL.into_iter().collect()
+    }
+
+    pub fn legacy() -> Self {
+        Capability::LEGACY.into_iter().collect()
+    }
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    pub fn insert(&mut self, capability: Capability) {
+        self.0 |= capability.bit();
+    }
+
+    pub fn remove(&mut self, capability: Capability) {
+        self.0 &= !capability.bit();
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Capability> {
+        Capability::ALL.into_iter().filter(move |capability| self.contains(*capability))
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        let mut set = Capabilities::default();
+        for capability in iter {
+            set.insert(capability);
+        }
+        set
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, capability) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(capability.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// `HELLO <version> <capability>...`, sent by the client first and answered
+/// in kind by the server with what it supports. Names a peer does not know
+/// are skipped, so either side can grow new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl fmt::Display for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HELLO {}", self.version)?;
+        if self.capabilities != Capabilities::default() {
+            write!(f, " {}", self.capabilities)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Hello {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("HELLO") {
+            return Err(format!("expected HELLO, got: {}", line));
+        }
+        let version = words.next()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| format!("malformed HELLO: {}", line))?;
+        let capabilities = words.filter_map(|word| word.parse().ok()).collect();
+        Ok(Hello { version, capabilities })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trips() {
+        for name in ["plain.txt", "with space", ".hidden", "a\\b", "tab\there", "line\nbreak\r", "ideo\u{3000}space.txt", "nb\u{a0}sp", "vt\x0bff\x0c", "bell\x07", "ünïcödé/日本.txt", "brace{1}", "back\\u{41}"] {
+            let escaped = escape_name(name);
+            assert_eq!(unescape_name(&escaped), name, "{:?} escaped as {:?}", name, escaped);
+            assert_eq!(escaped.split_whitespace().count(), 1, "{:?} escaped as {:?}", name, escaped);
+            assert_ne!(escaped, ".");
+        }
+    }
+
+    #[test]
+    fn escapes_whitespace_as_code_points() {
+        assert_eq!(escape_name("a b\tc"), "a\\sb\\tc");
+        assert_eq!(escape_name("ideo\u{3000}space"), "ideo\\u{3000}space");
+        assert_eq!(escape_name("."), "\\.");
+    }
+
+    #[test]
+    fn unescape_keeps_malformed_escapes() {
+        assert_eq!(unescape_name("\\u"), "u");
+        assert_eq!(unescape_name("\\u{zz}x"), "u{zz}x");
+        assert_eq!(unescape_name("\\u{110000}"), "u{110000}");
+        assert_eq!(unescape_name("\\q"), "q");
+        assert_eq!(unescape_name("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn entry_with_unicode_whitespace_round_trips() {
+        let entry = Entry { kind: EntryKind::File, size: 3, mtime: 7, md5: None, name: "ideo\u{3000}space.txt".to_string() };
+        let parsed = entry.to_string().parse::<Entry>().unwrap();
+        assert_eq!(parsed.name, entry.name);
+    }
+}
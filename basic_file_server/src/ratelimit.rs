@@ -0,0 +1,90 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// A transfer rate in bytes per second, written like `10MiB/s`, `500k` or
+/// `1000000`. Binary suffixes (`K`, `KiB`, `M`, `MiB`, `G`, `GiB`) count in
+/// 1024s, `KB`, `MB` and `GB` in 1000s; case and a trailing `/s` are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub u64);
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}B/s", self.0)
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_ascii_lowercase();
+        let spec = lower.strip_suffix("/s").unwrap_or(&lower);
+        let split = spec.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(spec.len());
+        let (number, unit) = spec.split_at(split);
+        let multiplier: u64 = match unit {
+            "" | "b" => 1,
+            "k" | "kib" => 1 << 10,
+            "kb" => 1_000,
+            "m" | "mib" => 1 << 20,
+            "mb" => 1_000_000,
+            "g" | "gib" => 1 << 30,
+            "gb" => 1_000_000_000,
+            _ => return Err(format!("invalid rate unit in {:?}", s)),
+        };
+        let number: f64 = number.parse().map_err(|_| format!("invalid rate: {:?}", s))?;
+        let rate = (number * multiplier as f64) as u64;
+        if rate == 0 {
+            return Err(format!("rate must be positive: {:?}", s));
+        }
+        Ok(Rate(rate))
+    }
+}
+
+/// Token bucket holding up to a tenth of a second of traffic (at least
+/// 64 KiB). Consuming more than is available is allowed and leaves the
+/// bucket in debt, so callers can charge for bytes after sending them.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> Self {
+        let rate = rate.0 as f64;
+        let burst = (rate / 10.0).max(64.0 * 1024.0);
+        Self { rate, burst, tokens: burst, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Bytes that may be sent now.
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens.max(0.0) as usize
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+
+    /// How long until `bytes` (capped at the burst size) are available.
+    pub fn wait_time(&mut self, bytes: usize) -> Duration {
+        self.refill();
+        let missing = (bytes as f64).min(self.burst) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+}
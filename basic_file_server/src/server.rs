@@ -1,340 +1,2905 @@
+use clap::Parser;
 use core::fmt;
+use globset::GlobBuilder;
 use std::fmt::Formatter;
 use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
-use std::fs::File;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, Metadata};
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
 use std::fmt::Debug;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::auth::{AuthConfig, Credentials};
+use crate::config::ServerConfig;
+use crate::checksum::{Algorithm, Checksum};
+use crate::compression::{Codec, Compression};
+use crate::index::{self, ChecksumIndex, INDEX_FILE};
+use crate::metrics::Metrics;
+use crate::protocol::{escape_name, unescape_name, ArchiveFormat, Capabilities, Capability, Entry, EntryKind, Hello, PROTOCOL_VERSION};
+use crate::ratelimit::{Rate, TokenBucket};
 
 const SERVER: Token = Token(0);
+const WAKER: Token = Token(1); // sockets waiting in a worker's channel, or shutdown
+const FIRST_CONNECTION: usize = 2;
 const CHUNK_SIZE: usize = 64 * 1024; // 64 KiB per write chunk
+const MAX_WRITE_BUF: usize = 256 * 1024; // most body data queued per fill_write_buf
+const HASH_POLL_INTERVAL: Duration = Duration::from_millis(10); // trailer waiting on a hash job
+const HASH_THREADS: usize = 2; // whole-file hashes for ranged GETs, across all connections
+const HASH_STEP: u64 = 1024 * 1024; // hashed between checks that a job is still wanted
+const AUTH_POLL_INTERVAL: Duration = Duration::from_millis(10); // AUTH waiting on the auth thread
+const UPLOAD_TMP_PREFIX: &str = ".upload-"; // in-progress PUTs, hidden from LIST
+const MAX_AUTH_FAILURES: u32 = 3; // then the connection is dropped
+const MAX_LINE_LEN: usize = 8 * 1024; // longest command line we buffer
+const MAX_QUEUED_RESPONSES: usize = 64; // pipelined responses waiting behind a transfer
+const MAX_MGET_FILES: usize = 10_000; // files one MGET may expand to
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_INDEX_INTERVAL: Duration = Duration::from_secs(300);
 
 #[derive(Debug)]
 enum OutgoingStage {
     Header,
     Body,
-    Trailing, // newline + MD5
+    Trailing, // newline + MD5/HASH line
     Done,
 }
 
 struct FileStreamer {
     file: File,
+    path: PathBuf,
+    metadata: Metadata,
     remaining: u64,
+    length: u64, // bytes of the file to send, before any compression
+    range: Option<(u64, u64)>, // (offset, total size) for ranged GETs
     stage: OutgoingStage,
-    md5_hex: Option<String>,
-    context: md5::Context,
+    digest_hex: Option<String>,
+    pending_digest: Option<Arc<HashJob>>, // whole-file hash of a ranged GET, started with its header
+    trailer: bool, // false for `HASH=none`: the body and its newline, nothing hashed
+    checksum: Box<dyn Checksum>,
+    legacy_trailer: bool, // plain `MD5 <hex>` for clients that did not ask for HASH=
+    name: Option<String>, // MGET frames each file as `MFILE <name> <size>`
+    compression: Option<Compression>,
+    compressor: Option<Box<dyn Codec>>, // until the whole body is compressed into `spool`
+    spool: Option<File>,
+    compressed_len: Option<u64>, // for the header; the spooled body is sent in its place
+    zero_copy: bool, // body goes file -> socket with sendfile, bypassing write_buf
+    scratch: Vec<u8>, // re-read buffer for hashing what sendfile sent
+    started: Instant, // header went out; for the transfer duration metric
 }
 
 impl Debug for FileStreamer {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.debug_struct("md5::Context")
+        f.debug_struct("FileStreamer")
         .field("file", &self.file)
         .field("remaining", &self.remaining)
+        .field("range", &self.range)
+        .field("name", &self.name)
+        .field("compression", &self.compression)
+        .field("zero_copy", &self.zero_copy)
         .field("stage", &self.stage)
-        .field("md5_hex", &self.md5_hex)
+        .field("checksum", &self.checksum)
+        .field("digest_hex", &self.digest_hex)
         .finish()
     }
 }
 
+/// What a GET sends of a file, and how.
+#[derive(Debug, Clone, Copy, Default)]
+struct StreamOptions {
+    offset: u64,
+    length: Option<u64>, // to the end of the file if None
+    hash: Option<Algorithm>, // None for clients that did not ask for HASH=
+    no_trailer: bool, // `HASH=none`
+    compress: Option<Compression>,
+}
+
 impl FileStreamer {
-    fn new(mut file: File) -> io::Result<Self> {
+    /// Stream `path` as `options` ask. The hash trailer covers the whole
+    /// file, uncompressed, so a resumed download can be verified once it
+    /// is complete. A `cached_digest` is sent as is instead of hashing.
+    fn new(path: PathBuf, options: StreamOptions, cached_digest: Option<String>) -> io::Result<Self> {
+        let StreamOptions { offset, length, hash, no_trailer, compress } = options;
+        let mut file = File::open(&path)?;
+        let metadata = file.metadata()?;
         let size = file.seek(SeekFrom::End(0))?;
-        file.seek(SeekFrom::Start(0))?;
+        if offset > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "offset beyond end of file"));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let ranged = offset > 0 || length.is_some();
+
+        let available = size - offset;
+        let length = length.map_or(available, |len| len.min(available));
         Ok(Self {
             file,
-            remaining: size,
+            path,
+            metadata,
+            remaining: length,
+            length,
+            range: ranged.then_some((offset, size)),
             stage: OutgoingStage::Header,
-            md5_hex: None,
-            context: md5::Context::new()
+            digest_hex: cached_digest,
+            pending_digest: None,
+            trailer: !no_trailer,
+            checksum: hash.unwrap_or_default().hasher(),
+            legacy_trailer: hash.is_none(),
+            name: None,
+            compression: compress,
+            compressor: compress.map(Compression::compressor).transpose()?,
+            spool: compress.map(|_| spool_file()).transpose()?,
+            compressed_len: None,
+            zero_copy: false,
+            scratch: Vec::new(),
+            started: Instant::now(),
         })
     }
+
+    /// Whether the body bytes go into `checksum` as they are sent.
+    fn hashes_body(&self) -> bool {
+        self.trailer && self.digest_hex.is_none() && self.range.is_none()
+    }
+
+    /// Whether the trailer needs a whole-file hash from the `HashPool`.
+    fn needs_hash_job(&self) -> bool {
+        self.trailer && self.digest_hex.is_none() && self.range.is_some() && self.pending_digest.is_none()
+    }
+
+    /// Compress up to MAX_WRITE_BUF more of the file into the spool, so
+    /// the header can give the compressed length. Once all of it is in,
+    /// the spool is sent as the body. Returns whether that is done.
+    fn compress_some(&mut self) -> io::Result<bool> {
+        let Some(mut compressor) = self.compressor.take() else {
+            return Ok(true);
+        };
+        let hash = self.hashes_body();
+        let Some(spool) = &mut self.spool else {
+            return Err(io::Error::other("compressed GET without a spool file"));
+        };
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut read = 0;
+        while self.remaining > 0 && read < MAX_WRITE_BUF {
+            let to_read = std::cmp::min(self.remaining as usize, CHUNK_SIZE);
+            let n = self.file.read(&mut buf[..to_read])?;
+            if n == 0 {
+                // unexpected EOF
+                self.remaining = 0;
+                break;
+            }
+            self.remaining -= n as u64;
+            read += n;
+            if hash {
+                self.checksum.update(&buf[..n]);
+            }
+            compressor.write(&buf[..n])?;
+            spool.write_all(&compressor.take_output())?;
+        }
+        if self.remaining > 0 {
+            self.compressor = Some(compressor);
+            return Ok(false);
+        }
+        spool.write_all(&compressor.finish()?)?;
+        let compressed_len = spool.stream_position()?;
+        spool.seek(SeekFrom::Start(0))?;
+        if let Some(spool) = self.spool.take() {
+            self.file = spool;
+        }
+        self.remaining = compressed_len;
+        self.compressed_len = Some(compressed_len);
+        Ok(true)
+    }
 }
 
+/// A temp file for a compressed body, unlinked as soon as it is created
+/// so it goes away with its handle however the transfer ends.
+fn spool_file() -> io::Result<File> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("bfs-spool-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+    let path = std::env::temp_dir().join(name);
+    let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
 
-#[derive(Debug)]
-struct Connection {
-    socket: TcpStream,
-    token: Token,
-    read_buf: Vec<u8>,
-    write_buf: Vec<u8>,
-    peer: SocketAddr,
-    current_streamer: Option<FileStreamer>,
+/// A version of a file and what to hash it with.
+type HashKey = (PathBuf, u64, Option<SystemTime>, Algorithm);
+
+/// One whole-file hash for the trailers of ranged GETs. Every transfer
+/// waiting for it holds a reference, and once the last one is dropped the
+/// job is given up.
+struct HashJob {
+    key: HashKey,
+    file: File,
+    result: Mutex<Option<Result<String, String>>>,
 }
 
-impl Connection {
-    fn new(socket: TcpStream, token: Token, peer: SocketAddr) -> Self {
-        Self {
-            socket,
-            token,
-            read_buf: Vec::with_capacity(4096),
-            write_buf: Vec::new(),
-            peer,
-            current_streamer: None,
+impl HashJob {
+    /// The digest, or None while the file is still being hashed.
+    fn result(&self) -> Option<io::Result<String>> {
+        self.result.lock().unwrap().clone().map(|result| result.map_err(io::Error::other))
+    }
+}
+
+/// A few threads, shared by every connection, hashing whole files for
+/// ranged GETs so the event loops never read what they do not send. A
+/// file already queued or being hashed is not hashed again.
+struct HashPool {
+    state: Arc<HashPoolState>,
+}
+
+#[derive(Default)]
+struct HashPoolState {
+    queue: Mutex<HashQueue>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct HashQueue {
+    jobs: VecDeque<Weak<HashJob>>,
+    pending: HashMap<HashKey, Weak<HashJob>>, // queued, running or done while still wanted
+    closed: bool,
+}
+
+impl Debug for HashPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let queue = self.state.queue.lock().unwrap();
+        f.debug_struct("HashPool")
+        .field("queued", &queue.jobs.len())
+        .field("pending", &queue.pending.len())
+        .finish()
+    }
+}
+
+impl HashPool {
+    fn new(threads: usize) -> io::Result<Self> {
+        let state = Arc::new(HashPoolState::default());
+        for id in 0..threads {
+            let state = Arc::clone(&state);
+            thread::Builder::new().name(format!("hash-{}", id)).spawn(move || state.work())?;
         }
+        Ok(Self { state })
     }
 
-    fn readable(&mut self) -> io::Result<Option<String>> {
-        println!("readable called, peer: {:?}", self.peer);
-        let mut buf = [0u8; 4096];
+    /// The job hashing all of `file`, as of `metadata`, joining the one
+    /// for the same version of `path` if there is one.
+    fn submit(&self, path: &Path, file: &File, metadata: &Metadata, algorithm: Algorithm) -> io::Result<Arc<HashJob>> {
+        let key = (path.to_path_buf(), metadata.len(), metadata.modified().ok(), algorithm);
+        let mut queue = self.state.queue.lock().unwrap();
+        queue.pending.retain(|_, job| job.strong_count() > 0);
+        if let Some(job) = queue.pending.get(&key).and_then(Weak::upgrade) {
+            trace!(path = %path.display(), "joining hash job");
+            return Ok(job);
+        }
+        let job = Arc::new(HashJob { key: key.clone(), file: file.try_clone()?, result: Mutex::default() });
+        queue.pending.insert(key, Arc::downgrade(&job));
+        queue.jobs.push_back(Arc::downgrade(&job));
+        self.state.ready.notify_one();
+        Ok(job)
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        self.state.queue.lock().unwrap().closed = true;
+        self.state.ready.notify_all();
+    }
+}
+
+impl HashPoolState {
+    /// A pool thread: run jobs until the pool is dropped. Jobs whose
+    /// transfers all went away, such as with their connection, are skipped
+    /// or stopped part way.
+    fn work(&self) {
+        while let Some(job) = self.next_job() {
+            let (path, size, _, algorithm) = &job.key;
+            let mut checksum = algorithm.hasher();
+            let mut buf = Vec::new();
+            let mut pos = 0;
+            let result = loop {
+                if pos == *size {
+                    break Some(Ok(checksum.hex_digest()));
+                }
+                if !self.wanted(&job) {
+                    debug!(path = %path.display(), "hash no longer wanted");
+                    break None;
+                }
+                let len = std::cmp::min(size - pos, HASH_STEP);
+                if let Err(e) = hash_at(&job.file, pos, len, checksum.as_mut(), &mut buf) {
+                    break Some(Err(e.to_string()));
+                }
+                pos += len;
+            };
+            *job.result.lock().unwrap() = result;
+        }
+    }
+
+    /// Wait for the next job still wanted; None once the pool is dropped.
+    fn next_job(&self) -> Option<Arc<HashJob>> {
+        let mut queue = self.queue.lock().unwrap();
         loop {
-            match self.socket.read(&mut buf) {
-                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client closed")),
-                Ok(n) => {
-                    self.read_buf.extend_from_slice(&buf[..n]);
-                    // Look for newline as command delimiter
-                    if let Some(pos) = self.read_buf.iter().position(|&b| b == b'\n') {
-                        let line = self.read_buf.drain(..=pos).collect::<Vec<u8>>();
-                        let line = String::from_utf8_lossy(&line).trim().to_string();
-                        return Ok(Some(line));
+            if queue.closed {
+                return None;
+            }
+            match queue.jobs.pop_front() {
+                Some(job) => {
+                    if let Some(job) = job.upgrade() {
+                        return Some(job);
                     }
-                    continue;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => return Err(e),
+                None => queue = self.ready.wait(queue).unwrap(),
             }
         }
-        Ok(None)
     }
 
-    fn writable(&mut self) -> io::Result<()> {
-        // Keep write_buf reasonable - don't buffer more than 256KB
-        const MAX_WRITE_BUF: usize = 256 * 1024;
-        
-        // First, try to write any existing buffered data
-        while !self.write_buf.is_empty() {
-            match self.socket.write(&self.write_buf) {
-                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write")),
-                Ok(n) => {
-                    self.write_buf.drain(..n);
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => return Err(e),
-            }
+    /// Whether a transfer still waits for `job`, the pool thread holding
+    /// the only other reference. Decided under the queue lock so `submit`
+    /// cannot join a job as it is given up.
+    fn wanted(&self, job: &Arc<HashJob>) -> bool {
+        if Arc::strong_count(job) > 1 {
+            return true;
         }
-        
-        // If there's an active FileStreamer, feed more data into write_buf
-        if let Some(streamer) = &mut self.current_streamer {
-            // Handle header stage
-            if matches!(streamer.stage, OutgoingStage::Header) {
-                let header = format!("FILE {}\n", streamer.remaining);
-                self.write_buf.extend_from_slice(header.as_bytes());
-                streamer.stage = OutgoingStage::Body;
-                println!("Sending FILE header: {} bytes", streamer.remaining);
-            }
-
-            // Stream body: keep filling write_buf as long as there's room and file data
-            if matches!(streamer.stage, OutgoingStage::Body) {
-                while streamer.remaining > 0 && self.write_buf.len() < MAX_WRITE_BUF {
-                    let to_read = std::cmp::min(
-                        std::cmp::min(streamer.remaining as usize, CHUNK_SIZE),
-                        MAX_WRITE_BUF - self.write_buf.len()
-                    );
-                    let mut tmp = vec![0u8; to_read];
-                    let n = streamer.file.read(&mut tmp)?;
-                    if n == 0 {
-                        // unexpected EOF
-                        streamer.remaining = 0;
+        let mut queue = self.queue.lock().unwrap();
+        if Arc::strong_count(job) > 1 {
+            return true;
+        }
+        queue.pending.remove(&job.key);
+        false
+    }
+}
+
+/// Feed `len` bytes of `file` starting at `pos` into `checksum`, without
+/// moving the file position.
+fn hash_at(file: &File, mut pos: u64, mut len: u64, checksum: &mut dyn Checksum, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.resize(CHUNK_SIZE, 0);
+    while len > 0 {
+        let n = std::cmp::min(len, CHUNK_SIZE as u64) as usize;
+        file.read_exact_at(&mut buf[..n], pos)?;
+        checksum.update(&buf[..n]);
+        pos += n as u64;
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+/// `GETDIR` response: a tar stream built on the fly, never staged on disk.
+/// The archive size is not known up front, so it goes out as `DATA <len>`
+/// chunks; `DATA 0` ends it and the hash trailer covers the archive bytes
+/// as sent (after compression).
+struct TarStreamer {
+    root: PathBuf,
+    format: ArchiveFormat,
+    entries: VecDeque<Entry>,
+    file: Option<File>,
+    remaining: u64, // body bytes left of the current member
+    padding: usize, // zeros after it, up to the next 512-byte block
+    finished: bool, // end-of-archive blocks written
+    encoder: Option<Box<dyn Codec>>,
+    stage: OutgoingStage,
+    checksum: Box<dyn Checksum>,
+    legacy_trailer: bool,
+    started: Instant, // header went out; for the transfer duration metric
+}
+
+impl Debug for TarStreamer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TarStreamer")
+        .field("root", &self.root)
+        .field("format", &self.format)
+        .field("entries", &self.entries.len())
+        .field("remaining", &self.remaining)
+        .field("stage", &self.stage)
+        .field("checksum", &self.checksum)
+        .finish()
+    }
+}
+
+impl TarStreamer {
+    /// `entries` come from `list_dir`, so every name is already confined to `root`.
+    fn new(root: PathBuf, entries: Vec<Entry>, format: ArchiveFormat, hash: Option<Algorithm>) -> io::Result<Self> {
+        let encoder = match format {
+            ArchiveFormat::Tar => None,
+            ArchiveFormat::TarZst => Some(Compression::Zstd.compressor()?),
+        };
+        Ok(Self {
+            root,
+            format,
+            entries: entries.into(),
+            file: None,
+            remaining: 0,
+            padding: 0,
+            finished: false,
+            encoder,
+            stage: OutgoingStage::Header,
+            checksum: hash.unwrap_or_default().hasher(),
+            legacy_trailer: hash.is_none(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Append the next framed output to `out` until it holds `max` bytes,
+    /// archiving at most `max` bytes of tar for it, since compressed output
+    /// may stay far smaller. Returns false once the trailer has been sent.
+    fn fill(&mut self, out: &mut Vec<u8>, max: usize) -> io::Result<bool> {
+        match self.stage {
+            OutgoingStage::Header => {
+                out.extend_from_slice(format!("ARCHIVE {}\n", self.format).as_bytes());
+                self.stage = OutgoingStage::Body;
+                self.started = Instant::now();
+            }
+            OutgoingStage::Body => {
+                let mut tar_bytes = Vec::with_capacity(CHUNK_SIZE);
+                let mut archived = 0;
+                while out.len() < max && archived < max {
+                    tar_bytes.clear();
+                    let more = self.next_tar_bytes(&mut tar_bytes)?;
+                    archived += tar_bytes.len();
+                    let chunk = match &mut self.encoder {
+                        Some(encoder) => {
+                            encoder.write(&tar_bytes)?;
+                            encoder.take_output()
+                        }
+                        None => std::mem::take(&mut tar_bytes),
+                    };
+                    self.checksum.update(&chunk);
+                    push_data_chunk(out, &chunk);
+                    if !more {
+                        if let Some(encoder) = self.encoder.take() {
+                            let tail = encoder.finish()?;
+                            self.checksum.update(&tail);
+                            push_data_chunk(out, &tail);
+                        }
+                        out.extend_from_slice(b"DATA 0\n");
+                        debug!(algorithm = %self.checksum.algorithm(), digest = %self.checksum.hex_digest(), "archive complete");
+                        self.stage = OutgoingStage::Trailing;
                         break;
                     }
-                    streamer.remaining -= n as u64;
-                    streamer.context.consume(&tmp[..n]);
-                    self.write_buf.extend_from_slice(&tmp[..n]);
-                }
-
-                if streamer.remaining == 0 {
-                    streamer.stage = OutgoingStage::Trailing;
-                    let digest = streamer.context.clone().finalize();
-                    let md5_hex = format!("{:x}", digest);
-                    println!("File transfer complete, MD5: {}", md5_hex);
-                    streamer.md5_hex = Some(md5_hex);
                 }
             }
+            OutgoingStage::Trailing => {
+                let hash_line = if self.legacy_trailer {
+                    format!("MD5 {}\n", self.checksum.hex_digest())
+                } else {
+                    format!("HASH {} {}\n", self.checksum.algorithm(), self.checksum.hex_digest())
+                };
+                out.extend_from_slice(hash_line.as_bytes());
+                self.stage = OutgoingStage::Done;
+            }
+            OutgoingStage::Done => return Ok(false),
+        }
+        Ok(true)
+    }
 
-            if matches!(streamer.stage, OutgoingStage::Trailing) {
-                self.write_buf.extend_from_slice(b"\n"); // newline after file
-                if let Some(ref md5) = streamer.md5_hex {
-                    let md5_line = format!("MD5 {}\n", md5);
-                    self.write_buf.extend_from_slice(md5_line.as_bytes());
+    /// Produce the next piece of the uncompressed tar stream: a member header
+    /// or up to CHUNK_SIZE of file data. Returns false once the archive is complete.
+    fn next_tar_bytes(&mut self, out: &mut Vec<u8>) -> io::Result<bool> {
+        if let Some(file) = &mut self.file {
+            if self.remaining > 0 {
+                let to_read = std::cmp::min(self.remaining, CHUNK_SIZE as u64) as usize;
+                let start = out.len();
+                out.resize(start + to_read, 0);
+                let n = file.read(&mut out[start..])?;
+                if n == 0 {
+                    // The file shrank after its header went out; keep the
+                    // archive well-formed by padding with zeros, like GNU tar
+                    warn!(?file, "file shrank while archiving");
+                    self.remaining -= to_read as u64;
+                } else {
+                    out.truncate(start + n);
+                    self.remaining -= n as u64;
                 }
-                streamer.stage = OutgoingStage::Done;
             }
+            if self.remaining == 0 {
+                out.resize(out.len() + self.padding, 0);
+                self.file = None;
+            }
+            return Ok(true);
         }
 
-        // Try to write again after potentially adding more data
-        while !self.write_buf.is_empty() {
-            match self.socket.write(&self.write_buf) {
-                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write")),
-                Ok(n) => {
-                    self.write_buf.drain(..n);
+        while let Some(entry) = self.entries.pop_front() {
+            // Re-resolve: the tree may have changed since it was listed
+            let opened = resolve_path(&self.root, &entry.name).and_then(|path| {
+                let file = File::open(&path)?;
+                let metadata = file.metadata()?;
+                Ok((file, metadata))
+            });
+            let (file, metadata) = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    warn!(entry = %entry.name, error = %e, "skipping archive entry");
+                    continue;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => return Err(e),
+            };
+            let size = if metadata.is_dir() { 0 } else { metadata.len() };
+            tar_header(&entry.name, &metadata, size, out)?;
+            if size > 0 {
+                self.file = Some(file);
+                self.remaining = size;
+                self.padding = (512 - (size % 512) as usize) % 512;
             }
+            return Ok(true);
         }
 
-        // If streamer exists and is done (all bytes queued and write_buf now empty), remove it
-        if let Some(streamer) = &self.current_streamer {
-            if matches!(streamer.stage, OutgoingStage::Done) && self.write_buf.is_empty() {
-                self.current_streamer = None;
-                println!("Streamer removed, transfer complete");
-            }
+        if !self.finished {
+            // Two zero blocks mark the end of the archive
+            out.resize(out.len() + 1024, 0);
+            self.finished = true;
+            return Ok(true);
         }
+        Ok(false)
+    }
+}
 
-        Ok(())
+/// Frame one piece of a body whose length is not known up front.
+fn push_data_chunk(out: &mut Vec<u8>, chunk: &[u8]) {
+    if chunk.is_empty() {
+        return; // DATA 0 is the end marker
     }
+    out.extend_from_slice(format!("DATA {}\n", chunk.len()).as_bytes());
+    out.extend_from_slice(chunk);
 }
 
-pub struct Server {
-    addr: String,
-    mount_dir: PathBuf,
+/// Write a GNU tar header for `name`. Symlinks were resolved by the caller
+/// and are archived as the file they point to. Names over 100 bytes get a
+/// GNU long-name member first.
+fn tar_header(name: &str, metadata: &Metadata, size: u64, out: &mut Vec<u8>) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    header.set_size(size);
+    let name = if metadata.is_dir() { format!("{}/", name) } else { name.to_string() };
+    if header.set_path(&name).is_err() {
+        let mut long = tar::Header::new_gnu();
+        long.set_entry_type(tar::EntryType::GNULongName);
+        long.set_path("././@LongLink")?;
+        long.set_mode(0o644);
+        long.set_size(name.len() as u64 + 1);
+        long.set_cksum();
+        out.extend_from_slice(long.as_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(512), 0);
+
+        let bytes = name.as_bytes();
+        let field = &mut header.as_old_mut().name;
+        let n = std::cmp::min(bytes.len(), field.len());
+        field[..n].copy_from_slice(&bytes[..n]);
+    }
+    header.set_cksum();
+    out.extend_from_slice(header.as_bytes());
+    Ok(())
 }
 
-impl Server {
-    pub fn new(addr: &str, mount_dir: PathBuf) -> Self {
-        Self { addr: addr.to_string(), mount_dir }
+struct FileUpload {
+    file: File,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    remaining: u64,
+    checksum: Box<dyn Checksum>,
+    committed: bool,
+}
+
+impl Debug for FileUpload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileUpload")
+        .field("file", &self.file)
+        .field("tmp_path", &self.tmp_path)
+        .field("final_path", &self.final_path)
+        .field("remaining", &self.remaining)
+        .field("checksum", &self.checksum)
+        .finish()
     }
+}
 
-    pub fn run(&mut self) -> io::Result<()> {
-        let mut poll = Poll::new()?;
-        let mut events = Events::with_capacity(256);
+impl FileUpload {
+    fn new(tmp_path: PathBuf, final_path: PathBuf, size: u64, algorithm: Algorithm) -> io::Result<Self> {
+        let file = File::options().write(true).create_new(true).open(&tmp_path)?;
+        Ok(Self {
+            file,
+            tmp_path,
+            final_path,
+            remaining: size,
+            checksum: algorithm.hasher(),
+            committed: false,
+        })
+    }
 
-        let addr = self.addr.parse::<SocketAddr>().expect("invalid socket addr");
-        let mut listener = TcpListener::bind(addr)?;
-        poll.registry().register(&mut listener, SERVER, Interest::READABLE)?;
+    fn receiving_body(&self) -> bool {
+        self.remaining > 0
+    }
 
-        let mut unique_token = 1usize;
-        let mut connections: HashMap<Token, Connection> = HashMap::new();
+    fn consume(&mut self, data: &[u8]) -> io::Result<()> {
+        self.checksum.update(data);
+        self.file.write_all(data)?;
+        self.remaining -= data.len() as u64;
+        Ok(())
+    }
 
-        println!("Server listening on {} and serving directory {:?}", self.addr, self.mount_dir);
+    /// Check the client's hash trailer and move the temp file into place.
+    /// Returns the locally computed digest on mismatch.
+    fn finish(mut self, hex: &str, checksums: &mut ChecksumIndex) -> io::Result<Result<(), String>> {
+        let ours = self.checksum.hex_digest();
+        if !ours.eq_ignore_ascii_case(hex) {
+            return Ok(Err(ours));
+        }
+        self.file.flush()?;
+        std::fs::rename(&self.tmp_path, &self.final_path)?;
+        self.committed = true;
+        let metadata = self.file.metadata()?;
+        checksums.insert(self.final_path.clone(), &metadata, self.checksum.algorithm(), ours);
+        Ok(Ok(()))
+    }
+}
 
-        loop {
-            poll.poll(&mut events, None)?;
+impl Drop for FileUpload {
+    fn drop(&mut self) {
+        // Aborted or rejected uploads must not leave temp files in the mount
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
 
-            for event in events.iter() {
-                match event.token() {
-                    SERVER => loop {
-                        match listener.accept() {
-                            Ok((socket, addr)) => {
-                                // mio sockets are already non-blocking
-                                let token = Token(unique_token);
-                                unique_token += 1;
-                                let conn = Connection::new(socket, token, addr);
-                                println!("new connection from {:?}", addr);
-                                poll.registry().register(&mut connections.entry(token).or_insert_with(|| conn).socket, token, Interest::READABLE.add(Interest::WRITABLE))?;
-                                // Ugly: we used entry to borrow socket; better to create then insert, but keep code short here
-                                // Instead, do actual insert properly below
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                            Err(e) => {
-                                eprintln!("accept error: {}", e);
-                                break;
-                            }
-                        }
-                    },
-                    tok => {
-                        println!("event for token: {:?}", tok);
-                        // get mutable connection
-                        if let Some(conn) = connections.get_mut(&tok) {
-                            println!("connection found for token: {:?}", tok);
-                            if event.is_readable() {
-                                println!("connection is readable, peer: {:?}", conn.peer);
-                                match conn.readable() {
-                                    Ok(Some(line)) => {
-                                        println!("command: {:?}", line);
-                                        if let Err(e) = handle_command(line, conn, &self.mount_dir) {
-                                            println!("error handling command from {:?}: {}", conn.peer, e);
-                                            eprintln!("error handling command from {:?}: {}", conn.peer, e);
-                                            connections.remove(&tok);
-                                            continue;
-                                        }
-                                    }
-                                    Ok(None) => {
-                                        println!("no command from {:?}", conn.peer);
-                                    }
-                                    Err(e) => {
-                                        eprintln!("read error from {:?}: {}", conn.peer, e);
-                                        connections.remove(&tok);
-                                        continue;
-                                    }
-                                }
-                            }
+/// The client socket, optionally wrapped in TLS. Connection only ever sees
+/// plaintext; when TLS is on, records that the socket could not take yet
+/// stay queued inside rustls and are pushed out on the next writable event.
+struct Transport {
+    socket: TcpStream,
+    tls: Option<rustls::ServerConnection>,
+}
 
-                            if event.is_writable() {
-                                println!("connection is writable");
-                                if let Err(e) = conn.writable() {
-                                    println!("write error to {:?}: {}", conn.peer, e);
-                                    eprintln!("write error to {:?}: {}", conn.peer, e);
-                                    connections.remove(&tok);
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                }
+impl Debug for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transport")
+        .field("socket", &self.socket)
+        .field("tls", &self.tls.is_some())
+        .finish()
+    }
+}
+
+impl Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tls) = &mut self.tls else {
+            return self.socket.read(buf);
+        };
+        loop {
+            // Hand out already decrypted data first; Ok(0) is a clean close_notify
+            match tls.reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
             }
+            if tls.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            tls.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // Handshake replies; if the socket is full they go out on the next writable event
+            flush_tls(tls, &mut self.socket)?;
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(tls) = &mut self.tls else {
+            return self.socket.write(data);
+        };
+        if !flush_tls(tls, &mut self.socket)? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        // rustls only buffers up to its limit, so this may accept a prefix
+        let n = tls.writer().write(data)?;
+        flush_tls(tls, &mut self.socket)?;
+        if n == 0 && !data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(n)
+    }
+
+    /// Push queued TLS records to the socket. Returns false if it would block.
+    fn flush(&mut self) -> io::Result<bool> {
+        match &mut self.tls {
+            Some(tls) => flush_tls(tls, &mut self.socket),
+            None => Ok(true),
+        }
+    }
 
-            // accept new connections properly after handling events (to avoid borrow issues)
-            // NOTE: simplified: accept loop above used entry().or_insert_with which is awkward; for clarity, we keep
-            // this TODO: refactor accept insertion if you rework code.
+    fn wants_write(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    /// Copy up to `count` bytes from the current position of `file` straight
+    /// to the socket with sendfile(2), advancing the file position. Plain TCP only.
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &File, count: usize) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        debug_assert!(self.tls.is_none());
+        // SAFETY: both descriptors stay open for the duration of the call, and
+        // a null offset makes the kernel use and advance the file position
+        let n = unsafe { libc::sendfile(self.socket.as_raw_fd(), file.as_raw_fd(), std::ptr::null_mut(), count) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
         }
+        Ok(n as usize)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_file(&mut self, _file: &File, _count: usize) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
     }
 }
 
-fn handle_command(line: String, conn: &mut Connection, mount_dir: &Path) -> io::Result<()> {
-    println!("recieved command: {}", line);
-    let parts: Vec<&str> = line.split_whitespace().collect();
-    if parts.is_empty() {
-        return Ok(());
+fn flush_tls(tls: &mut rustls::ServerConnection, socket: &mut TcpStream) -> io::Result<bool> {
+    while tls.wants_write() {
+        match tls.write_tls(socket) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
     }
-    println!("parts: {:?}", parts);
-    match parts[0] {
-        "LIST" => {
-            let mut out = Vec::new();
-            for entry in std::fs::read_dir(mount_dir)? {
-                let entry = entry?;
-                if entry.path().is_file() {
-                    if let Some(name) = entry.file_name().to_str() {
-                        out.extend_from_slice(name.as_bytes());
-                        out.push(b'\n');  // Send each filename on its own line
-                    }
-                }
-            }
-            out.extend_from_slice(b".\n");  // End marker on its own line
-            conn.write_buf.extend_from_slice(&out);
-            println!("LIST response prepared: {} bytes", out.len());
+    Ok(true)
+}
+
+/// A response waiting behind the active file transfer, in command order.
+#[derive(Debug)]
+enum Outgoing {
+    Bytes(Vec<u8>),
+    File(Box<FileStreamer>),
+    Archive(Box<TarStreamer>),
+    /// An MGET match, only opened once it reaches the front so a large
+    /// batch does not hold hundreds of files open.
+    Named { path: PathBuf, name: String, hash: Option<Algorithm> },
+}
+
+/// The token buckets a connection's body bytes are charged to: its own
+/// and the one shared by the whole server.
+#[derive(Debug, Default)]
+struct RateLimits {
+    connection: Option<TokenBucket>,
+    global: Option<Arc<Mutex<TokenBucket>>>,
+    resume_at: Option<Instant>, // throttled or yielded; the event loop retries writing then
+}
+
+impl RateLimits {
+    /// Body bytes that may be queued now, at most `max`. Zero means the
+    /// connection is throttled until `resume_at`; so does less than a chunk,
+    /// or the write loop would spin on the trickle refilling the buckets.
+    fn allowance(&mut self, max: usize) -> usize {
+        let mut allowance = max;
+        let mut wait = Duration::ZERO;
+        if let Some(bucket) = &mut self.connection {
+            allowance = allowance.min(bucket.available());
+            wait = wait.max(bucket.wait_time(CHUNK_SIZE));
         }
-        "GET" => {
-            if parts.len() < 2 {
-                conn.write_buf.extend_from_slice(b"ERR missing filename
-");
-                return Ok(());
-            }
-            let filename = parts[1];
-            let safe = sanitize_filename(filename);
-            let full = mount_dir.join(&safe);
-            if !full.exists() || !full.is_file() {
-                conn.write_buf.extend_from_slice(b"ERR file not found
-");
-                return Ok(());
+        if let Some(global) = &self.global {
+            let mut bucket = global.lock().unwrap();
+            allowance = allowance.min(bucket.available());
+            wait = wait.max(bucket.wait_time(CHUNK_SIZE));
+        }
+        if allowance < max.min(CHUNK_SIZE) {
+            self.resume_at = Some(Instant::now() + wait);
+            return 0;
+        }
+        allowance
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        if let Some(bucket) = &mut self.connection {
+            bucket.consume(bytes);
+        }
+        if let Some(global) = &self.global {
+            global.lock().unwrap().consume(bytes);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    transport: Transport,
+    token: Token,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    current_streamer: Option<FileStreamer>,
+    current_archive: Option<TarStreamer>,
+    queued: VecDeque<Outgoing>,
+    input_paused: bool, // stopped taking commands because `queued` is full
+    current_upload: Option<FileUpload>,
+    discarding: Option<u64>, // body bytes of a refused PUT still to skip; Some(0) awaits its trailer
+    checksums: Arc<RwLock<ChecksumIndex>>,
+    hashes: Arc<HashPool>,
+    user: Option<String>, // set once AUTH succeeds
+    pending_auth: Option<(String, Receiver<bool>)>, // AUTH being checked for this user; no commands meanwhile
+    auth_checker: Arc<AuthChecker>,
+    auth_failures: u32,
+    protocol: u32, // 0 until the peer sends HELLO
+    capabilities: Capabilities, // what HELLO negotiated; what predates it for version 0
+    zero_copy: bool, // plain GET bodies may use sendfile
+    limits: RateLimits,
+    metrics: Arc<Metrics>,
+    span: tracing::Span, // token and peer on everything logged for this connection
+    last_active: Instant, // last event on the socket, for the idle timeout
+    line_started: Option<Instant>, // first byte of a command line still incomplete
+    _slot: ConnectionSlot,
+}
+
+impl Connection {
+    /// `zero_copy` only takes effect on Linux and without TLS, since
+    /// sendfile bypasses the encryption layer.
+    fn new(transport: Transport, token: Token, peer: SocketAddr, shared: &Shared, slot: ConnectionSlot) -> Self {
+        let zero_copy = shared.zero_copy && transport.tls.is_none() && cfg!(target_os = "linux");
+        let limits = RateLimits {
+            connection: shared.rate_limit.map(TokenBucket::new),
+            global: shared.global_limit.clone(),
+            resume_at: None,
+        };
+        Self {
+            transport,
+            token,
+            read_buf: Vec::with_capacity(4096),
+            write_buf: Vec::new(),
+            current_streamer: None,
+            current_archive: None,
+            queued: VecDeque::new(),
+            input_paused: false,
+            current_upload: None,
+            discarding: None,
+            checksums: Arc::clone(&shared.checksums),
+            hashes: Arc::clone(&shared.hashes),
+            user: None,
+            pending_auth: None,
+            auth_checker: Arc::clone(&shared.auth_checker),
+            auth_failures: 0,
+            protocol: 0,
+            // Version 0 peers get the features they had before HELLO and
+            // nothing newer, such as extra header fields they cannot parse
+            capabilities: Capabilities::legacy(),
+            zero_copy,
+            limits,
+            metrics: Arc::clone(&shared.metrics),
+            span: tracing::info_span!("conn", token = token.0, %peer),
+            last_active: Instant::now(),
+            // The first command line is due from the moment we accept
+            line_started: Some(Instant::now()),
+            _slot: slot,
+        }
+    }
+
+    /// Whether this connection negotiated every capability in `required`.
+    /// If not, answers `ERR` naming the first one missing.
+    fn negotiated(&mut self, required: impl IntoIterator<Item = Capability>) -> bool {
+        match required.into_iter().find(|capability| !self.capabilities.contains(*capability)) {
+            None => true,
+            Some(missing) => {
+                self.respond(format!("ERR {} not negotiated\n", missing).as_bytes());
+                false
             }
+        }
+    }
 
-            let file = File::open(&full)?;
-            let streamer = FileStreamer::new(file)?;
+    /// Open `path` for sending, using sendfile when possible. A current
+    /// whole-file digest from the index is sent as is instead of hashing.
+    fn open_streamer(&self, path: PathBuf, options: StreamOptions) -> io::Result<FileStreamer> {
+        let zero_copy = self.zero_copy && options.compress.is_none();
+        let metadata = std::fs::metadata(&path)?;
+        let cached_digest = self.checksums.read().unwrap().get(&path, &metadata, options.hash.unwrap_or_default()).map(str::to_string);
+        let mut streamer = FileStreamer::new(path, options, cached_digest)?;
+        streamer.zero_copy = zero_copy;
+        Ok(streamer)
+    }
 
-            // Prepare: header will be queued on writable
-            conn.current_streamer = Some(streamer);
+    /// Settle an AUTH whose check has finished. Returns false while one is
+    /// still being checked, and commands after it have to wait.
+    fn poll_auth(&mut self) -> io::Result<bool> {
+        let Some((user, result)) = &self.pending_auth else {
+            return Ok(true);
+        };
+        let verified = match result.try_recv() {
+            Ok(verified) => verified,
+            Err(TryRecvError::Empty) => {
+                self.limits.resume_at = Some(Instant::now() + AUTH_POLL_INTERVAL);
+                return Ok(false);
+            }
+            Err(TryRecvError::Disconnected) => return Err(io::Error::other("auth thread has exited")),
+        };
+        let user = user.clone();
+        self.pending_auth = None;
+        if verified {
+            info!(%user, "authenticated");
+            self.respond(format!("OK {}\n", escape_name(&user)).as_bytes());
+            self.user = Some(user);
+            return Ok(true);
         }
-        _ => {
-            conn.write_buf.extend_from_slice(b"ERR unknown command
-");
+        self.auth_failures += 1;
+        warn!(attempts = self.auth_failures, "authentication failed");
+        self.respond(b"ERR authentication failed\n");
+        if self.auth_failures >= MAX_AUTH_FAILURES {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "too many failed AUTH attempts"));
         }
+        Ok(true)
     }
-    Ok(())
-}
 
-fn sanitize_filename(name: &str) -> String {
-    name.replace("..", "").replace("/", "").replace("\\", "")
-}
\ No newline at end of file
+    /// Return the next complete command line, reading from the socket only
+    /// once the buffered lines are used up. Ok(None) means the socket would
+    /// block, or that the response queue is full and input is paused until
+    /// `writable` drains it.
+    fn readable(&mut self) -> io::Result<Option<String>> {
+        trace!("readable called");
+        let mut buf = [0u8; 4096];
+        self.input_paused = false;
+        loop {
+            // Raw PUT body bytes go straight to the upload, never through the line parser
+            if let Some(upload) = &mut self.current_upload
+                && upload.receiving_body()
+                && !self.read_buf.is_empty()
+            {
+                let n = std::cmp::min(upload.remaining, self.read_buf.len() as u64) as usize;
+                upload.consume(&self.read_buf[..n])?;
+                self.read_buf.drain(..n);
+                self.line_started = None; // body bytes, not a slow command line
+            }
+            if let Some(remaining) = &mut self.discarding
+                && *remaining > 0
+                && !self.read_buf.is_empty()
+            {
+                let n = std::cmp::min(*remaining, self.read_buf.len() as u64) as usize;
+                *remaining -= n as u64;
+                self.read_buf.drain(..n);
+                self.line_started = None;
+            }
+
+            let receiving_body = self.current_upload.as_ref().is_some_and(FileUpload::receiving_body)
+                || self.discarding.is_some_and(|remaining| remaining > 0);
+            if !receiving_body {
+                if self.queued.len() >= MAX_QUEUED_RESPONSES {
+                    self.input_paused = true;
+                    return Ok(None);
+                }
+                // Look for newline as command delimiter
+                if let Some(pos) = self.read_buf.iter().position(|&b| b == b'\n') {
+                    let line = self.read_buf.drain(..=pos).collect::<Vec<u8>>();
+                    let line = String::from_utf8_lossy(&line).trim().to_string();
+                    // Complete lines already buffered are not the client's delay
+                    let partial = !self.read_buf.is_empty() && !self.read_buf.contains(&b'\n');
+                    self.line_started = partial.then(Instant::now);
+                    return Ok(Some(line));
+                }
+                if self.read_buf.len() > MAX_LINE_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "command line too long"));
+                }
+            }
+
+            match self.transport.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client closed")),
+                Ok(n) => {
+                    if !receiving_body && self.line_started.is_none() {
+                        self.line_started = Some(Instant::now());
+                    }
+                    self.read_buf.extend_from_slice(&buf[..n]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Queue a response (a line or a whole LIST block) behind anything
+    /// still being sent, so pipelined commands are answered in order.
+    fn respond(&mut self, bytes: &[u8]) {
+        // Multi-line responses are LIST blocks, whose entries may look like anything
+        if let Some(message) = bytes.strip_prefix(b"ERR ")
+            && bytes.iter().filter(|&&b| b == b'\n').count() == 1
+        {
+            self.metrics.error(&String::from_utf8_lossy(message));
+        }
+        if !self.sending() {
+            self.write_buf.extend_from_slice(bytes);
+        } else if let Some(Outgoing::Bytes(last)) = self.queued.back_mut() {
+            last.extend_from_slice(bytes);
+        } else {
+            self.queued.push_back(Outgoing::Bytes(bytes.to_vec()));
+        }
+    }
+
+    /// Start a file transfer, or queue it behind the active one.
+    fn queue_file(&mut self, streamer: FileStreamer) {
+        if !self.sending() {
+            self.current_streamer = Some(streamer);
+        } else {
+            self.queued.push_back(Outgoing::File(Box::new(streamer)));
+        }
+    }
+
+    fn queue_archive(&mut self, archive: TarStreamer) {
+        if !self.sending() {
+            self.current_archive = Some(archive);
+        } else {
+            self.queued.push_back(Outgoing::Archive(Box::new(archive)));
+        }
+    }
+
+    /// Whether a transfer is in progress or responses are waiting behind one.
+    fn sending(&self) -> bool {
+        self.current_streamer.is_some() || self.current_archive.is_some() || !self.queued.is_empty()
+    }
+
+    /// Move the next queued response into play. Returns false if there is none.
+    fn dequeue(&mut self) -> bool {
+        match self.queued.pop_front() {
+            Some(Outgoing::Bytes(bytes)) => self.write_buf.extend_from_slice(&bytes),
+            Some(Outgoing::File(streamer)) => self.current_streamer = Some(*streamer),
+            Some(Outgoing::Archive(archive)) => self.current_archive = Some(*archive),
+            Some(Outgoing::Named { path, name, hash }) => match self.open_streamer(path, StreamOptions { hash, ..StreamOptions::default() }) {
+                Ok(mut streamer) => {
+                    streamer.name = Some(name);
+                    self.current_streamer = Some(streamer);
+                }
+                Err(e) => {
+                    // Removed or replaced since MGET was expanded; the batch goes on
+                    warn!(%name, error = %e, "MGET file skipped");
+                    let reason = if e.kind() == io::ErrorKind::NotFound { "file not found" } else { "read error" };
+                    self.metrics.error(reason);
+                    self.write_buf.extend_from_slice(format!("ERR {} {}\n", escape_name(&name), reason).as_bytes());
+                }
+            },
+            None => return false,
+        }
+        true
+    }
+
+    /// Why this connection should be closed at `now`, if it has timed out.
+    fn timed_out(&self, now: Instant, timeouts: &Timeouts) -> Option<&'static str> {
+        if let (Some(started), Some(limit)) = (self.line_started, timeouts.header)
+            && now >= started + limit
+        {
+            return Some("command line not completed in time");
+        }
+        if let Some(limit) = timeouts.idle
+            && now >= self.last_active + limit
+        {
+            return Some("idle");
+        }
+        None
+    }
+
+    /// When the event loop next has to look at this connection without an
+    /// event: a timeout, or the end of rate-limit throttling.
+    fn next_deadline(&self, timeouts: &Timeouts) -> Option<Instant> {
+        let header = self.line_started.zip(timeouts.header).map(|(started, limit)| started + limit);
+        let idle = timeouts.idle.map(|limit| self.last_active + limit);
+        [self.limits.resume_at, header, idle].into_iter().flatten().min()
+    }
+
+    /// Whether a transfer is in flight either way, which a graceful
+    /// shutdown waits for.
+    fn busy(&self) -> bool {
+        self.has_pending_output() || self.current_upload.is_some() || self.discarding.is_some_and(|remaining| remaining > 0)
+    }
+
+    fn has_pending_output(&self) -> bool {
+        !self.write_buf.is_empty() || self.sending() || self.transport.wants_write()
+    }
+
+    fn writable(&mut self) -> io::Result<()> {
+        // Edge-triggered: keep going until the socket would block or there is
+        // nothing left to send, otherwise no further writable event arrives
+        while self.flush_write_buf()? && self.fill_write_buf()? {}
+        Ok(())
+    }
+
+    /// Write buffered data to the socket. Returns false if the socket would block.
+    fn flush_write_buf(&mut self) -> io::Result<bool> {
+        while !self.write_buf.is_empty() {
+            match self.transport.write(&self.write_buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write")),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                    self.metrics.bytes_sent(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        // With TLS the last records may still be queued inside rustls
+        self.transport.flush()
+    }
+
+    /// Stop filling write_buf and let the other connections on this event
+    /// loop have a turn; the loop calls `writable` again on its next pass.
+    /// Compressed bodies need this, since the socket never pushes back on
+    /// input that compresses to next to nothing. Returns false, for
+    /// `fill_write_buf` to hand back.
+    fn yield_turn(&mut self) -> bool {
+        self.limits.resume_at = Some(Instant::now());
+        false
+    }
+
+    /// Feed more data from the active FileStreamer, or the next queued
+    /// response, into write_buf. Returns false once there is nothing more to queue.
+    fn fill_write_buf(&mut self) -> io::Result<bool> {
+        if let Some(archive) = &mut self.current_archive {
+            let mut max = MAX_WRITE_BUF;
+            let in_body = matches!(archive.stage, OutgoingStage::Body);
+            if in_body {
+                max = self.limits.allowance(MAX_WRITE_BUF);
+                if max == 0 {
+                    return Ok(!self.write_buf.is_empty());
+                }
+            }
+            let before = self.write_buf.len();
+            let more = archive.fill(&mut self.write_buf, max)?;
+            if in_body {
+                self.limits.charge(self.write_buf.len() - before);
+            }
+            if more && in_body && archive.encoder.is_some() {
+                return Ok(self.yield_turn());
+            }
+            if more {
+                return Ok(true);
+            }
+            self.metrics.archive_served(archive.started.elapsed());
+            self.current_archive = None;
+            trace!("archive removed, transfer complete");
+            return Ok(self.dequeue());
+        }
+
+        let Some(streamer) = &mut self.current_streamer else {
+            return Ok(self.dequeue());
+        };
+
+        // Handle header stage
+        if matches!(streamer.stage, OutgoingStage::Header) {
+            // Only once the transfer is under way, so pipelined GETs do not
+            // queue more than one job per connection
+            if streamer.needs_hash_job() {
+                streamer.pending_digest = Some(self.hashes.submit(&streamer.path, &streamer.file, &streamer.metadata, streamer.checksum.algorithm())?);
+            }
+            // A compressed body is spooled first, a bounded step per turn
+            if !streamer.compress_some()? {
+                return Ok(self.yield_turn());
+            }
+            // Plain GETs keep the original single-field header
+            let mut header = match (&streamer.name, streamer.range) {
+                (Some(name), _) => format!("MFILE {} {}", escape_name(name), streamer.length),
+                (None, Some((offset, total))) => format!("FILE {} {} {}", streamer.length, offset, total),
+                (None, None) => format!("FILE {}", streamer.length),
+            };
+            // Sizes stay uncompressed; COMPRESSED= is what the body takes on the wire
+            if let (Some(compression), Some(compressed_len)) = (streamer.compression, streamer.compressed_len) {
+                header.push_str(&format!(" COMPRESS={} COMPRESSED={}", compression, compressed_len));
+            }
+            // A digest from the index lets the client check before the body
+            // comes; only for peers that asked for it in HELLO
+            if self.capabilities.contains(Capability::Digest)
+                && let Some(digest_hex) = &streamer.digest_hex
+            {
+                header.push_str(&format!(" DIGEST={}:{}", streamer.checksum.algorithm(), digest_hex));
+            }
+            header.push('\n');
+            self.write_buf.extend_from_slice(header.as_bytes());
+            streamer.stage = OutgoingStage::Body;
+            streamer.started = Instant::now();
+            trace!(bytes = streamer.remaining, "sending FILE header");
+        }
+
+        // Rate limits only ever hold back body bytes
+        let mut max = MAX_WRITE_BUF;
+        if matches!(streamer.stage, OutgoingStage::Body) && streamer.remaining > 0 {
+            max = self.limits.allowance(MAX_WRITE_BUF);
+            if max == 0 {
+                return Ok(!self.write_buf.is_empty());
+            }
+        }
+        let body_start = self.write_buf.len();
+
+        // Stream body: sendfile straight to the socket, or keep filling
+        // write_buf as long as there's room and file data
+        if matches!(streamer.stage, OutgoingStage::Body) && streamer.zero_copy {
+            // The header (and anything before it) has to be on the wire first
+            if !self.write_buf.is_empty() {
+                return Ok(true);
+            }
+            if streamer.remaining > 0 {
+                let count = std::cmp::min(streamer.remaining, max as u64) as usize;
+                let pos = streamer.file.stream_position()?;
+                match self.transport.send_file(&streamer.file, count) {
+                    Ok(0) => streamer.remaining = 0, // unexpected EOF
+                    Ok(n) => {
+                        self.limits.charge(n);
+                        self.metrics.bytes_sent(n);
+                        if streamer.hashes_body() {
+                            // Re-read from the page cache for the hash; no socket-bound copy
+                            hash_at(&streamer.file, pos, n as u64, streamer.checksum.as_mut(), &mut streamer.scratch)?;
+                        }
+                        streamer.remaining -= n as u64;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) => return Err(e),
+                }
+            }
+        } else if matches!(streamer.stage, OutgoingStage::Body) {
+            // A compressed body was hashed as it was spooled
+            let hash = streamer.compression.is_none() && streamer.hashes_body();
+            while streamer.remaining > 0 && self.write_buf.len() - body_start < max {
+                let to_read = std::cmp::min(
+                    std::cmp::min(streamer.remaining as usize, CHUNK_SIZE),
+                    max - (self.write_buf.len() - body_start)
+                );
+                let mut tmp = vec![0u8; to_read];
+                let n = streamer.file.read(&mut tmp)?;
+                if n == 0 {
+                    // unexpected EOF
+                    streamer.remaining = 0;
+                    break;
+                }
+                streamer.remaining -= n as u64;
+                if hash {
+                    streamer.checksum.update(&tmp[..n]);
+                }
+                self.write_buf.extend_from_slice(&tmp[..n]);
+            }
+            self.limits.charge(self.write_buf.len() - body_start);
+        }
+        if matches!(streamer.stage, OutgoingStage::Body) && streamer.remaining == 0 {
+            streamer.stage = OutgoingStage::Trailing;
+            if streamer.hashes_body() {
+                let algorithm = streamer.checksum.algorithm();
+                let digest_hex = streamer.checksum.hex_digest();
+                self.checksums.write().unwrap().insert(streamer.path.clone(), &streamer.metadata, algorithm, digest_hex.clone());
+                streamer.digest_hex = Some(digest_hex);
+            }
+            debug!(path = %streamer.path.display(), algorithm = %streamer.checksum.algorithm(), digest = streamer.digest_hex.as_deref().unwrap_or_default(), "file transfer complete");
+        } else if matches!(streamer.stage, OutgoingStage::Trailing) {
+            if let Some(job) = &streamer.pending_digest {
+                let Some(result) = job.result() else {
+                    // Come back for it instead of blocking the event loop
+                    self.limits.resume_at = Some(Instant::now() + HASH_POLL_INTERVAL);
+                    return Ok(false);
+                };
+                let digest_hex = result?;
+                self.checksums.write().unwrap().insert(streamer.path.clone(), &streamer.metadata, streamer.checksum.algorithm(), digest_hex.clone());
+                streamer.digest_hex = Some(digest_hex);
+                streamer.pending_digest = None;
+            }
+            self.write_buf.extend_from_slice(b"\n"); // newline after file
+            if let Some(ref digest_hex) = streamer.digest_hex
+                && streamer.trailer
+            {
+                let hash_line = if streamer.legacy_trailer {
+                    format!("MD5 {}\n", digest_hex)
+                } else {
+                    format!("HASH {} {}\n", streamer.checksum.algorithm(), digest_hex)
+                };
+                self.write_buf.extend_from_slice(hash_line.as_bytes());
+            }
+            streamer.stage = OutgoingStage::Done;
+        } else if matches!(streamer.stage, OutgoingStage::Done) {
+            // All bytes queued and write_buf already flushed, remove it
+            self.metrics.file_served(streamer.started.elapsed());
+            self.current_streamer = None;
+            trace!("streamer removed, transfer complete");
+            return Ok(self.dequeue());
+        }
+
+        Ok(true)
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ServerCli {
+    /// address to listen on, e.g. 127.0.0.1:4000
+    #[arg(required_unless_present = "config")]
+    pub addr: Option<SocketAddr>,
+
+    /// directory to serve
+    #[arg(required_unless_present = "config")]
+    pub mount: Option<PathBuf>,
+
+    /// TOML file with the server settings; flags given here override it
+    #[arg(long, short = 'c')]
+    pub config: Option<PathBuf>,
+
+    /// PEM certificate chain; enables TLS together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// pre-shared token accepted by `AUTH TOKEN`; may be repeated
+    #[arg(long, env = "BFS_AUTH_TOKEN")]
+    pub auth_token: Vec<String>,
+
+    /// users file for `AUTH USER`, lines from the hash-password subcommand
+    #[arg(long)]
+    pub auth_users: Option<PathBuf>,
+
+    /// always copy file bodies through user space instead of using sendfile
+    #[arg(long)]
+    pub no_zero_copy: bool,
+
+    /// event loop threads to spread connections over [default: 1]
+    #[arg(long)]
+    pub workers: Option<usize>,
+
+    /// per-connection send limit, e.g. 10MiB/s
+    #[arg(long)]
+    pub rate_limit: Option<Rate>,
+
+    /// send limit shared by all connections, e.g. 100MiB/s
+    #[arg(long)]
+    pub global_rate_limit: Option<Rate>,
+
+    /// most open connections; more are refused with `ERR busy`
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// most open connections from one IP address
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// seconds without traffic before a connection is closed; 0 disables [default: 300]
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// seconds a client gets to finish a command line; 0 disables [default: 30]
+    #[arg(long)]
+    pub header_timeout: Option<u64>,
+
+    /// serve Prometheus metrics at http://<addr>/metrics
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// seconds to let transfers finish after SIGTERM/SIGINT [default: 30]
+    #[arg(long)]
+    pub drain_timeout: Option<u64>,
+
+    /// keep MD5s of the mount in a `.bfs-index` file, refreshed in the background
+    #[arg(long)]
+    pub index: bool,
+
+    /// seconds between scans of the mount for the index [default: 300]
+    #[arg(long)]
+    pub index_interval: Option<u64>,
+}
+
+pub struct Server {
+    addr: String,
+    mount_dir: PathBuf,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    auth: Arc<AuthConfig>,
+    zero_copy: bool,
+    workers: usize,
+    rate_limit: Option<Rate>,
+    global_rate_limit: Option<Rate>,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    metrics_addr: Option<SocketAddr>,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+    handle_signals: bool,
+    index_interval: Option<Duration>,
+    site: ReloadHandle,
+    reload_source: Option<ReloadSource>,
+}
+
+/// Produces the mount directory and credentials SIGHUP switches to.
+type ReloadSource = Arc<dyn Fn() -> io::Result<(PathBuf, AuthConfig)> + Send + Sync>;
+
+impl Server {
+    pub fn new(addr: &str, mount_dir: PathBuf) -> Self {
+        Self { addr: addr.to_string(), mount_dir, tls: None, auth: Arc::new(AuthConfig::default()), zero_copy: true, workers: 1, rate_limit: None, global_rate_limit: None,
+            timeouts: Timeouts { idle: Some(DEFAULT_IDLE_TIMEOUT), header: Some(DEFAULT_HEADER_TIMEOUT) },
+            max_connections: None, max_connections_per_ip: None, metrics_addr: None,
+            shutdown: ShutdownHandle::default(), drain_timeout: DEFAULT_DRAIN_TIMEOUT, handle_signals: false, index_interval: None,
+            site: ReloadHandle::default(), reload_source: None }
+    }
+
+    /// Require a successful `AUTH` before any other command.
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// Send plain GET bodies with sendfile where possible (the default).
+    pub fn with_zero_copy(mut self, enabled: bool) -> Self {
+        self.zero_copy = enabled;
+        self
+    }
+
+    /// Build a server from the command line, filling in anything it leaves
+    /// unset from `file` (the `--config` file, already loaded). SIGHUP
+    /// reloads the mount directory and credentials from both again.
+    pub fn from_cli(cli: ServerCli, file: ServerConfig) -> io::Result<Self> {
+        let addr = cli.addr.or(file.listen).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on: pass ADDR or set `listen` in the config file")
+        })?;
+        let (mount, auth) = site_settings(&cli, &file)?;
+        let mut server = Server::new(&addr.to_string(), mount)
+            .with_auth(auth)
+            .with_zero_copy(!cli.no_zero_copy && file.zero_copy.unwrap_or(true))
+            .with_workers(cli.workers.or(file.workers).unwrap_or(1));
+        let limits = &file.limits;
+        if let Some(rate) = cli.rate_limit.or(limits.rate) {
+            server = server.with_rate_limit(rate);
+        }
+        if let Some(rate) = cli.global_rate_limit.or(limits.global_rate) {
+            server = server.with_global_rate_limit(rate);
+        }
+        let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let idle = cli.idle_timeout.or(limits.idle_timeout).unwrap_or(DEFAULT_IDLE_TIMEOUT.as_secs());
+        let header = cli.header_timeout.or(limits.header_timeout).unwrap_or(DEFAULT_HEADER_TIMEOUT.as_secs());
+        server = server
+            .with_idle_timeout(seconds(idle))
+            .with_header_timeout(seconds(header));
+        if let Some(max) = cli.max_connections.or(limits.max_connections) {
+            server = server.with_max_connections(max);
+        }
+        if let Some(max) = cli.max_connections_per_ip.or(limits.max_connections_per_ip) {
+            server = server.with_max_connections_per_ip(max);
+        }
+        if let Some(addr) = cli.metrics_addr.or(file.metrics_addr) {
+            server = server.with_metrics_addr(addr);
+        }
+        let drain = cli.drain_timeout.or(file.drain_timeout).map_or(DEFAULT_DRAIN_TIMEOUT, Duration::from_secs);
+        server = server
+            .with_drain_timeout(drain)
+            .with_signal_handling(true);
+        if cli.index || file.index == Some(true) {
+            let interval = cli.index_interval.or(file.index_interval).map_or(DEFAULT_INDEX_INTERVAL, Duration::from_secs);
+            server = server.with_index(interval.max(Duration::from_secs(1)));
+        }
+        if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+            server = server.with_tls(crate::tls::server_config(cert, key)?);
+        } else if let Some(tls) = &file.tls {
+            server = server.with_tls(crate::tls::server_config(&tls.cert, &tls.key)?);
+        }
+        Ok(server.with_reload(move || {
+            let file = match &cli.config {
+                Some(path) => ServerConfig::load(path)?,
+                None => ServerConfig::default(),
+            };
+            site_settings(&cli, &file)
+        }))
+    }
+
+    /// Serve every connection over TLS with the given configuration.
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Spread connections over `workers` event loops, each on its own
+    /// thread, so a blocking disk read only stalls the connections sharing
+    /// its loop. With one worker (the default) everything runs on the
+    /// thread calling `run`.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Cap what each connection sends, in body bytes per second.
+    pub fn with_rate_limit(mut self, rate: Rate) -> Self {
+        self.rate_limit = Some(rate);
+        self
+    }
+
+    /// Cap what all connections together send, across every worker.
+    pub fn with_global_rate_limit(mut self, rate: Rate) -> Self {
+        self.global_rate_limit = Some(rate);
+        self
+    }
+
+    /// Close connections that see no traffic for this long (`None` never does).
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    /// Close connections that take longer than this to send a command
+    /// line, counting the first one from connect.
+    pub fn with_header_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.header = timeout;
+        self
+    }
+
+    /// Turn away connections beyond this many open ones.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Turn away connections beyond this many from one IP address.
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP at `addr`/metrics.
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// How long a shutdown waits for transfers in flight before closing
+    /// their connections anyway.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Shut down gracefully on SIGTERM or SIGINT; a second signal exits at once.
+    pub fn with_signal_handling(mut self, enabled: bool) -> Self {
+        self.handle_signals = enabled;
+        self
+    }
+
+    /// Hash every file in the mount every `interval` and keep the digests
+    /// in `.bfs-index` there, so they survive restarts and `LIST -l`,
+    /// `STAT` and `GET` have them without reading the file.
+    pub fn with_index(mut self, interval: Duration) -> Self {
+        self.index_interval = Some(interval);
+        self
+    }
+
+    /// Where SIGHUP gets the mount directory and credentials to switch to.
+    /// Without one, SIGHUP is ignored.
+    pub fn with_reload(mut self, source: impl Fn() -> io::Result<(PathBuf, AuthConfig)> + Send + Sync + 'static) -> Self {
+        self.reload_source = Some(Arc::new(source));
+        self
+    }
+
+    /// A handle that swaps the mount directory and credentials of the
+    /// running server.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.site.clone()
+    }
+
+    /// A handle that makes `run` stop accepting, finish the transfers in
+    /// flight and return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let addr = self.addr.parse::<SocketAddr>().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address to listen on {:?}: {}", self.addr, e))
+        })?;
+        let mut listener = TcpListener::bind(addr)?;
+
+        self.site.install(&self.mount_dir, Arc::clone(&self.auth))?;
+        let shared = Arc::new(Shared {
+            site: self.site.clone(),
+            tls: self.tls.clone(),
+            zero_copy: self.zero_copy,
+            checksums: Arc::default(),
+            hashes: Arc::new(HashPool::new(HASH_THREADS)?),
+            auth_checker: Arc::new(AuthChecker::spawn()?),
+            rate_limit: self.rate_limit,
+            global_limit: self.global_rate_limit.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+            timeouts: self.timeouts,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            counts: Arc::default(),
+            metrics: Arc::default(),
+            shutdown: self.shutdown.clone(),
+            drain_timeout: self.drain_timeout,
+            next_token: AtomicUsize::new(FIRST_CONNECTION),
+        });
+        if self.handle_signals {
+            let reload = self.reload_source.clone().map(|source| (self.site.clone(), source));
+            handle_signals(self.shutdown.clone(), reload)?;
+        }
+
+        info!(addr = %self.addr, mount = %shared.site.current().mount_dir.display(), "server listening");
+        if let Some(interval) = self.index_interval {
+            spawn_indexer(shared.site.clone(), Arc::clone(&shared.checksums), interval, shared.shutdown.clone())?;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            let shared = Arc::clone(&shared);
+            crate::metrics::serve(metrics_addr, move || {
+                let active = shared.counts.lock().unwrap().total;
+                shared.metrics.render(active)
+            })?;
+        }
+
+        if self.workers == 1 {
+            let poll = Poll::new()?;
+            poll.registry().register(&mut listener, SERVER, Interest::READABLE)?;
+            shared.shutdown.register(Arc::new(Waker::new(poll.registry(), WAKER)?));
+            EventLoop::new(poll, shared).run(Some(listener), None)?;
+            info!("server stopped");
+            return Ok(());
+        }
+
+        let workers = (0..self.workers)
+            .map(|id| Worker::spawn(id, Arc::clone(&shared)))
+            .collect::<io::Result<Vec<_>>>()?;
+        info!(workers = workers.len(), "running worker event loops");
+
+        let mut poll = Poll::new()?;
+        let mut events = Events::with_capacity(16);
+        poll.registry().register(&mut listener, SERVER, Interest::READABLE)?;
+        shared.shutdown.register(Arc::new(Waker::new(poll.registry(), WAKER)?));
+        let mut next = 0;
+        while !shared.shutdown.is_requested() {
+            match poll.poll(&mut events, None) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            if events.iter().any(|event| event.token() == SERVER) {
+                accept_all(&listener, &shared, |accepted| {
+                    let worker = &workers[next];
+                    next = (next + 1) % workers.len();
+                    worker.hand_off(accepted)
+                })?;
+            }
+        }
+
+        // Closing the listener makes new clients fail fast instead of
+        // waiting in the backlog while the workers drain
+        drop(listener);
+        for worker in workers {
+            worker.join();
+        }
+        info!("server stopped");
+        Ok(())
+    }
+}
+
+/// Stops a running `Server`: it closes its listener, lets transfers in
+/// flight finish for up to the drain timeout, and `run` returns.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    wakers: Mutex<Vec<Arc<Waker>>>, // one per Poll, to interrupt its wait
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let wakers = self.inner.wakers.lock().unwrap();
+        self.inner.requested.store(true, Ordering::SeqCst);
+        for waker in wakers.iter() {
+            if let Err(e) = waker.wake() {
+                warn!(error = %e, "could not wake event loop for shutdown");
+            }
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Wake `waker` on shutdown, or right away if that already happened.
+    fn register(&self, waker: Arc<Waker>) {
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        if self.is_requested() {
+            let _ = waker.wake();
+        }
+        wakers.push(waker);
+    }
+}
+
+/// Swaps the mount directory and credentials of a running `Server`.
+/// Connections stay open and use the new ones from their next command;
+/// transfers already under way finish from the old mount.
+#[derive(Debug, Clone, Default)]
+pub struct ReloadHandle {
+    site: Arc<RwLock<Option<Arc<Site>>>>,
+}
+
+/// What a reload replaces.
+#[derive(Debug)]
+struct Site {
+    mount_dir: PathBuf, // canonical, since path confinement compares against it
+    auth: Arc<AuthConfig>,
+}
+
+impl ReloadHandle {
+    pub fn reload(&self, mount_dir: &Path, auth: AuthConfig) -> io::Result<()> {
+        if self.site.read().unwrap().is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "server is not running"));
+        }
+        self.install(mount_dir, Arc::new(auth))?;
+        info!(mount = %self.current().mount_dir.display(), "reloaded mount and credentials");
+        Ok(())
+    }
+
+    fn install(&self, mount_dir: &Path, auth: Arc<AuthConfig>) -> io::Result<()> {
+        let mount_dir = mount_dir.canonicalize()
+            .map_err(|e| io::Error::new(e.kind(), format!("mount {}: {}", mount_dir.display(), e)))?;
+        if !mount_dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("mount {} is not a directory", mount_dir.display())));
+        }
+        *self.site.write().unwrap() = Some(Arc::new(Site { mount_dir, auth }));
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<Site> {
+        let site = self.site.read().unwrap();
+        Arc::clone(site.as_ref().expect("site is installed before serving"))
+    }
+}
+
+/// The mount directory and credentials from the command line, or else from
+/// the config file. Credentials given on the command line replace the
+/// file's rather than adding to them.
+fn site_settings(cli: &ServerCli, file: &ServerConfig) -> io::Result<(PathBuf, AuthConfig)> {
+    let mount = cli.mount.clone().or(file.mount.clone()).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no directory to serve: pass MOUNT or set `mount` in the config file")
+    })?;
+    let mut auth = AuthConfig::default();
+    let tokens = if cli.auth_token.is_empty() { &file.auth.tokens } else { &cli.auth_token };
+    for token in tokens {
+        auth.add_token(token);
+    }
+    if let Some(path) = &cli.auth_users {
+        auth.load_users(path)?;
+    } else {
+        if let Some(path) = &file.auth.users_file {
+            auth.load_users(path)?;
+        }
+        auth.add_users(file.auth.users.iter().map(String::as_str), "[auth] users")?;
+    }
+    Ok((mount, auth))
+}
+
+/// Turn the first SIGTERM or SIGINT into a graceful shutdown; a second one
+/// exits immediately. SIGHUP reloads the mount and credentials, if there
+/// is somewhere to reload them from.
+fn handle_signals(shutdown: ShutdownHandle, reload: Option<(ReloadHandle, ReloadSource)>) -> io::Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT])?;
+    if reload.is_some() {
+        signals.add_signal(SIGHUP)?;
+    }
+    thread::Builder::new().name("signals".into()).spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                if let Some((site, source)) = &reload {
+                    info!("SIGHUP, reloading");
+                    if let Err(e) = source().and_then(|(mount, auth)| site.reload(&mount, auth)) {
+                        error!(error = %e, "reload failed, keeping the current settings");
+                    }
+                }
+                continue;
+            }
+            if shutdown.is_requested() {
+                warn!(signal, "second signal, exiting without draining");
+                std::process::exit(1);
+            }
+            info!(signal, "shutdown requested");
+            shutdown.shutdown();
+        }
+    })?;
+    Ok(())
+}
+
+/// Keep the checksum index of the current mount up to date, scanning it
+/// every `interval` and saving the index whenever a scan changed it. The
+/// saved index is loaded first, so only files changed since are hashed.
+fn spawn_indexer(site: ReloadHandle, checksums: Arc<RwLock<ChecksumIndex>>, interval: Duration, shutdown: ShutdownHandle) -> io::Result<()> {
+    thread::Builder::new().name("index".into()).spawn(move || {
+        let mut loaded: Option<PathBuf> = None;
+        while !shutdown.is_requested() {
+            let root = site.current().mount_dir.clone();
+            if loaded.as_ref() != Some(&root) {
+                match checksums.write().unwrap().load(&root) {
+                    Ok(count) => info!(mount = %root.display(), digests = count, "loaded checksum index"),
+                    Err(e) => warn!(error = %e, "cannot load checksum index, rebuilding it"),
+                }
+                loaded = Some(root.clone());
+            }
+            match index::scan(&checksums, &root, is_hidden) {
+                Ok(stats) => debug!(files = stats.files, hashed = stats.hashed, removed = stats.removed, "checksum index scanned"),
+                Err(e) => warn!(error = %e, mount = %root.display(), "checksum index scan failed"),
+            }
+            let snapshot = checksums.write().unwrap().snapshot(&root);
+            if let Some(contents) = snapshot
+                && let Err(e) = index::save(&root, &contents) {
+                warn!(error = %e, "cannot save checksum index");
+            }
+
+            // Sleep in short steps so shutdown is not held up by a long interval
+            let deadline = Instant::now() + interval;
+            while !shutdown.is_requested() && Instant::now() < deadline {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()).min(Duration::from_secs(1)));
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    idle: Option<Duration>, // no events at all on the connection
+    header: Option<Duration>, // a command line started but not finished
+}
+
+/// Open connections, counted where they are accepted so the limits hold
+/// across all workers.
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// A connection's place in `ConnectionCounts`, given back when dropped.
+#[derive(Debug)]
+struct ConnectionSlot {
+    counts: Arc<Mutex<ConnectionCounts>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Server state every event loop reads. The checksum index is the only
+/// part connections write to.
+struct Shared {
+    site: ReloadHandle,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    zero_copy: bool,
+    checksums: Arc<RwLock<ChecksumIndex>>,
+    hashes: Arc<HashPool>,
+    auth_checker: Arc<AuthChecker>,
+    rate_limit: Option<Rate>,
+    global_limit: Option<Arc<Mutex<TokenBucket>>>,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    counts: Arc<Mutex<ConnectionCounts>>,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+    next_token: AtomicUsize, // connection tokens are unique across event loops
+}
+
+impl Shared {
+    /// Count a new connection from `addr`, or say which limit it exceeds.
+    fn admit(&self, addr: SocketAddr) -> Result<ConnectionSlot, &'static str> {
+        let ip = addr.ip();
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_connections.is_some_and(|max| counts.total >= max) {
+            return Err("too many connections");
+        }
+        let from_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_connections_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err("too many connections from this address");
+        }
+        counts.total += 1;
+        *counts.per_ip.entry(ip).or_default() += 1;
+        self.metrics.connection_opened();
+        Ok(ConnectionSlot { counts: Arc::clone(&self.counts), ip })
+    }
+}
+
+/// Checks AUTH credentials on a thread of its own. Password checks are
+/// slow by design and would stall every connection on the event loop.
+/// Attempts from all connections take turns, which also paces guessing.
+#[derive(Debug)]
+struct AuthChecker {
+    jobs: Sender<(Arc<AuthConfig>, Credentials, Sender<bool>)>,
+}
+
+impl AuthChecker {
+    fn spawn() -> io::Result<Self> {
+        let (jobs, queue) = mpsc::channel::<(Arc<AuthConfig>, Credentials, Sender<bool>)>();
+        thread::Builder::new().name("auth".into()).spawn(move || {
+            for (auth, credentials, reply) in queue {
+                // The connection may be gone by now
+                let _ = reply.send(auth.verify(&credentials));
+            }
+        })?;
+        Ok(Self { jobs })
+    }
+
+    /// Queue `credentials` to be checked against `auth`; the result
+    /// arrives on the receiver.
+    fn check(&self, auth: Arc<AuthConfig>, credentials: Credentials) -> io::Result<Receiver<bool>> {
+        let (reply, result) = mpsc::channel();
+        self.jobs.send((auth, credentials, reply)).map_err(|_| io::Error::other("auth thread has exited"))?;
+        Ok(result)
+    }
+}
+
+/// One mio `Poll` and the connections registered with it.
+struct EventLoop {
+    poll: Poll,
+    connections: HashMap<Token, Connection>,
+    shared: Arc<Shared>,
+    draining: Option<Instant>, // shutting down; connections are closed at this deadline
+}
+
+impl EventLoop {
+    fn new(poll: Poll, shared: Arc<Shared>) -> Self {
+        Self { poll, connections: HashMap::new(), shared, draining: None }
+    }
+
+    /// Serve until shutdown has drained every connection, or polling fails.
+    /// New sockets come from `listener` when this loop accepts for itself,
+    /// or over `incoming` (announced by `WAKER`) when the accept thread
+    /// hands them out.
+    fn run(&mut self, mut listener: Option<TcpListener>, incoming: Option<&Receiver<Accepted>>) -> io::Result<()> {
+        let mut events = Events::with_capacity(256);
+        let shared = Arc::clone(&self.shared);
+        loop {
+            if self.draining.is_none() && shared.shutdown.is_requested() {
+                listener = None; // stop accepting
+                self.start_draining(shared.drain_timeout);
+            }
+            if let Some(deadline) = self.draining {
+                if self.connections.is_empty() {
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    warn!(connections = self.connections.len(), "drain timeout, closing remaining connections");
+                    self.connections.clear();
+                    return Ok(());
+                }
+            }
+
+            // Sleep no longer than the first timeout or throttled connection
+            let now = Instant::now();
+            let timeout = self.connections.values()
+                .filter_map(|conn| conn.next_deadline(&shared.timeouts))
+                .chain(self.draining)
+                .min()
+                .map(|at| at.saturating_duration_since(now));
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                // A signal, such as one asking for shutdown, cut the wait short
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    SERVER => {
+                        if let Some(listener) = &listener {
+                            accept_all(listener, &shared, |accepted| self.add_connection(accepted))?;
+                        }
+                    }
+                    WAKER => {
+                        for accepted in incoming.into_iter().flat_map(Receiver::try_iter) {
+                            // Handed over just before shutdown; dropping closes it
+                            if self.draining.is_none() && !shared.shutdown.is_requested() {
+                                self.add_connection(accepted)?;
+                            }
+                        }
+                    }
+                    tok => {
+                        trace!(token = tok.0, "event for token");
+                        self.drive(tok, event.is_readable(), event.is_writable());
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            let due: Vec<Token> = self.connections.iter()
+                .filter(|(_, conn)| conn.next_deadline(&shared.timeouts).is_some_and(|at| at <= now))
+                .map(|(tok, _)| *tok)
+                .collect();
+            for tok in due {
+                let Some(conn) = self.connections.get_mut(&tok) else {
+                    continue;
+                };
+                if let Some(reason) = conn.timed_out(now, &shared.timeouts) {
+                    conn.span.in_scope(|| info!(reason, "closing connection"));
+                    self.connections.remove(&tok);
+                } else if conn.limits.resume_at.is_some_and(|at| at <= now) {
+                    conn.limits.resume_at = None;
+                    self.drive(tok, false, true);
+                }
+            }
+        }
+    }
+
+    /// Close connections with nothing in flight and stop taking commands
+    /// on the rest; they go once their transfers are done.
+    fn start_draining(&mut self, timeout: Duration) {
+        self.draining = Some(Instant::now() + timeout);
+        self.connections.retain(|_, conn| conn.busy());
+        info!(connections = self.connections.len(), "shutting down, draining transfers in flight");
+    }
+
+    fn add_connection(&mut self, (socket, addr, slot): Accepted) -> io::Result<()> {
+        // mio sockets are already non-blocking
+        let tls = match &self.shared.tls {
+            Some(config) => match rustls::ServerConnection::new(Arc::clone(config)) {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    warn!(peer = %addr, error = %e, "tls setup failed");
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        let token = Token(self.shared.next_token.fetch_add(1, Ordering::Relaxed));
+        let mut conn = Connection::new(Transport { socket, tls }, token, addr, &self.shared, slot);
+        conn.span.in_scope(|| info!("new connection"));
+        self.poll.registry().register(&mut conn.transport.socket, token, Interest::READABLE.add(Interest::WRITABLE))?;
+        self.connections.insert(token, conn);
+        Ok(())
+    }
+
+    /// Run a connection after a readiness event, or once its rate limit
+    /// lets it write again.
+    fn drive(&mut self, tok: Token, readable: bool, writable: bool) {
+        let draining = self.draining.is_some();
+        // get mutable connection
+        let Some(conn) = self.connections.get_mut(&tok) else {
+            return;
+        };
+        conn.last_active = Instant::now();
+        let span = conn.span.clone();
+        let _entered = span.enter();
+        let site = self.shared.site.current();
+        // An AUTH being checked holds back the commands behind it
+        let mut read_now = readable || conn.pending_auth.is_some();
+        let result = loop {
+            // While draining, only an upload's body is still read
+            if read_now && (!draining || conn.current_upload.is_some()) {
+                trace!("connection is readable");
+                if let Err(e) = process_input(conn, &site.mount_dir, &site.auth) {
+                    break Err(e);
+                }
+            }
+
+            // Responses queued by handle_command (or TLS handshake records)
+            // need flushing even if this event carried no writable readiness
+            if writable || conn.has_pending_output() {
+                trace!("connection is writable");
+                if let Err(e) = conn.writable() {
+                    warn!(error = %e, "write error");
+                    break Err(e);
+                }
+            }
+
+            // Commands held back by a full response queue can run again
+            // once writing has made room; no new event will announce them
+            read_now = conn.input_paused && conn.queued.len() < MAX_QUEUED_RESPONSES;
+            if !read_now || draining {
+                break Ok(());
+            }
+        };
+        if result.is_err() || (draining && !conn.busy()) {
+            self.connections.remove(&tok);
+        }
+    }
+}
+
+/// The accept thread's side of a worker event loop: sockets go over the
+/// channel, then the waker makes the worker's poll pick them up.
+struct Worker {
+    sender: Sender<Accepted>,
+    waker: Arc<Waker>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn(id: usize, shared: Arc<Shared>) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        shared.shutdown.register(Arc::clone(&waker));
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new().name(format!("worker-{}", id)).spawn(move || {
+            let mut event_loop = EventLoop::new(poll, shared);
+            if let Err(e) = event_loop.run(None, Some(&receiver)) {
+                error!(worker = id, error = %e, "worker event loop stopped");
+            }
+        })?;
+        Ok(Self { sender, waker, thread })
+    }
+
+    fn hand_off(&self, accepted: Accepted) -> io::Result<()> {
+        self.sender.send(accepted)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "worker event loop has exited"))?;
+        self.waker.wake()
+    }
+
+    /// Wait for the worker to finish draining after shutdown.
+    fn join(self) {
+        drop(self.sender);
+        if self.thread.join().is_err() {
+            error!("worker thread panicked");
+        }
+    }
+}
+
+/// A socket that passed the connection limits, with the slot it holds.
+type Accepted = (TcpStream, SocketAddr, ConnectionSlot);
+
+/// Accept until the listener would block, passing on each socket the
+/// connection limits admit. The others get `ERR busy` and are closed.
+fn accept_all(listener: &TcpListener, shared: &Shared, mut handle: impl FnMut(Accepted) -> io::Result<()>) -> io::Result<()> {
+    loop {
+        match listener.accept() {
+            Ok((mut socket, addr)) => match shared.admit(addr) {
+                Ok(slot) => handle((socket, addr, slot))?,
+                Err(reason) => {
+                    warn!(peer = %addr, reason, "rejecting connection");
+                    shared.metrics.error("busy");
+                    // A TLS client could not read a plaintext line anyway,
+                    // and a handshake is too much work to spend on a refusal
+                    if shared.tls.is_none() {
+                        let _ = socket.write_all(b"ERR busy\n");
+                    }
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => {
+                error!(error = %e, "accept failed");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Handle every complete command line that is buffered or readable now, in
+/// order. Keeps reading until the socket would block, otherwise an upload
+/// body sitting behind its PUT line is never consumed.
+fn process_input(conn: &mut Connection, mount_dir: &Path, auth: &Arc<AuthConfig>) -> io::Result<()> {
+    loop {
+        if !conn.poll_auth()? {
+            return Ok(());
+        }
+        match conn.readable() {
+            Ok(Some(line)) => {
+                if let Err(e) = handle_command(line, conn, mount_dir, auth) {
+                    warn!(error = %e, "error handling command");
+                    return Err(e);
+                }
+            }
+            Ok(None) => {
+                trace!("no complete command");
+                return Ok(());
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                debug!("client closed connection");
+                return Err(e);
+            }
+            Err(e) => {
+                warn!(error = %e, "read error");
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn handle_command(line: String, conn: &mut Connection, mount_dir: &Path, auth: &Arc<AuthConfig>) -> io::Result<()> {
+    // AUTH lines carry secrets, never echo them
+    if !line.starts_with("AUTH ") {
+        debug!(command = %line, "received command");
+    }
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.is_empty() {
+        return Ok(());
+    }
+    if conn.discarding.take().is_some() && matches!(parts[0], "MD5" | "HASH") {
+        return Ok(()); // trailer of a refused PUT, which was already answered
+    }
+    conn.metrics.command(parts[0]);
+    if !matches!(parts[0], "AUTH" | "HELLO") && auth.is_enabled() && conn.user.is_none() {
+        conn.respond(b"ERR authentication required\n");
+        conn.discarding = put_size(&parts);
+        return Ok(());
+    }
+    match parts[0] {
+        "HELLO" => {
+            // HELLO <version> <capability>...; answered with our version and
+            // everything we support, and both sides use what they share
+            let Some(hello) = line.parse::<Hello>().ok().filter(|hello| hello.version > 0) else {
+                conn.respond(b"ERR usage: HELLO <version> <capability>...\n");
+                return Ok(());
+            };
+            if conn.protocol != 0 {
+                conn.respond(b"ERR HELLO already sent\n");
+                return Ok(());
+            }
+            let mut ours = Capabilities::all();
+            if !auth.is_enabled() {
+                ours.remove(Capability::Auth);
+            }
+            conn.protocol = hello.version.min(PROTOCOL_VERSION);
+            conn.capabilities = ours.intersection(hello.capabilities);
+            debug!(version = conn.protocol, capabilities = %conn.capabilities, "negotiated protocol");
+            conn.respond(format!("{}\n", Hello { version: PROTOCOL_VERSION, capabilities: ours }).as_bytes());
+        }
+        "AUTH" => {
+            if !conn.negotiated([Capability::Auth]) {
+                return Ok(());
+            }
+            // AUTH TOKEN <token> | AUTH USER <name> <password>
+            let credentials = match parts[1..] {
+                ["TOKEN", token] => Credentials::Token(unescape_name(token)),
+                ["USER", user, password] => Credentials::Password { user: unescape_name(user), password: unescape_name(password) },
+                _ => {
+                    conn.respond(b"ERR usage: AUTH TOKEN <token> | AUTH USER <name> <password>\n");
+                    return Ok(());
+                }
+            };
+            if !auth.is_enabled() {
+                conn.respond(b"OK authentication not required\n");
+                return Ok(());
+            }
+            let user = match &credentials {
+                Credentials::Token(_) => "token".to_string(),
+                Credentials::Password { user, .. } => user.clone(),
+            };
+            // Answered by poll_auth once the auth thread is done
+            let result = conn.auth_checker.check(Arc::clone(auth), credentials)?;
+            conn.pending_auth = Some((user, result));
+        }
+        "LIST" => {
+            // LIST [-l] [-r] [dir]; flags may also be combined as -lr
+            let mut long = false;
+            let mut recursive = false;
+            let mut args = parts[1..].iter().peekable();
+            while let Some(flags) = args.next_if(|arg| arg.starts_with('-')) {
+                for flag in flags.chars().skip(1) {
+                    match flag {
+                        'l' => long = true,
+                        'r' => recursive = true,
+                        _ => {
+                            conn.respond(b"ERR unknown LIST flag\n");
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            if long && !conn.negotiated([Capability::ListLong]) {
+                return Ok(());
+            }
+            let dir_arg = args.next().map_or_else(|| ".".to_string(), |arg| unescape_name(arg));
+            let dir = match resolve_path(mount_dir, &dir_arg) {
+                Ok(dir) if dir.is_dir() => dir,
+                Ok(_) => {
+                    conn.respond(b"ERR not a directory\n");
+                    return Ok(());
+                }
+                Err(e) => {
+                    conn.respond(resolve_error(&e).as_bytes());
+                    return Ok(());
+                }
+            };
+
+            let mut entries = Vec::new();
+            let listed = list_dir(mount_dir, &dir, recursive, &conn.checksums.read().unwrap(), &mut entries);
+            if let Err(e) = listed {
+                warn!(dir = %dir.display(), error = %e, "cannot list directory");
+                conn.respond(resolve_error(&e).as_bytes());
+                return Ok(());
+            }
+            let mut out = Vec::new();
+            for entry in entries {
+                // Send each entry on its own line; plain LIST only ever showed files
+                if long {
+                    out.extend_from_slice(entry.to_string().as_bytes());
+                } else if entry.kind != EntryKind::Dir {
+                    out.extend_from_slice(escape_name(&entry.name).as_bytes());
+                } else {
+                    continue;
+                }
+                out.push(b'\n');
+            }
+            out.extend_from_slice(b".\n");  // End marker on its own line
+            conn.respond(&out);
+            trace!(bytes = out.len(), "LIST response prepared");
+        }
+        "STAT" => {
+            // STAT <name>: the LIST -l line for one file or directory
+            if !conn.negotiated([Capability::Stat]) {
+                return Ok(());
+            }
+            let [_, name] = parts[..] else {
+                conn.respond(b"ERR usage: STAT <name>\n");
+                return Ok(());
+            };
+            let name = unescape_name(name);
+            // resolve_path keeps links from leading out of the mount; the
+            // entry is for the name as given, so a symlink shows as one
+            let entry = resolve_path(mount_dir, &name)
+                .and_then(|_| normalize_relative(&name))
+                .and_then(|relative| entry_for(mount_dir, &mount_dir.join(relative), &conn.checksums.read().unwrap()));
+            match entry {
+                Ok(Some(entry)) => conn.respond(format!("{}\n", entry).as_bytes()),
+                Ok(None) => conn.respond(b"ERR file not found\n"),
+                Err(e) => conn.respond(resolve_error(&e).as_bytes()),
+            }
+        }
+        "GET" => {
+            // GET <name> [<offset> [<length>]] [HASH=<alg>|none] [COMPRESS=zstd|gzip]
+            let (args, options) = match split_options(&parts[1..], true) {
+                Ok(split) => split,
+                Err(e) => {
+                    conn.respond(format!("ERR {}\n", e).as_bytes());
+                    return Ok(());
+                }
+            };
+            if args.is_empty() {
+                conn.respond(b"ERR missing filename
+");
+                return Ok(());
+            }
+            let ranged = args.len() > 1;
+            if !conn.negotiated(options.requires().chain(ranged.then_some(Capability::Range))) {
+                return Ok(());
+            }
+            let offset = match args.get(1).map(|s| s.parse::<u64>()) {
+                None => 0,
+                Some(Ok(offset)) => offset,
+                Some(Err(_)) => {
+                    conn.respond(b"ERR invalid offset\n");
+                    return Ok(());
+                }
+            };
+            let length = match args.get(2).map(|s| s.parse::<u64>()) {
+                None => None,
+                Some(Ok(length)) => Some(length),
+                Some(Err(_)) => {
+                    conn.respond(b"ERR invalid length\n");
+                    return Ok(());
+                }
+            };
+            let full = match resolve_path(mount_dir, &unescape_name(args[0])) {
+                Ok(full) => full,
+                Err(e) => {
+                    conn.respond(resolve_error(&e).as_bytes());
+                    return Ok(());
+                }
+            };
+            if !full.is_file() {
+                conn.respond(b"ERR file not found
+");
+                return Ok(());
+            }
+
+            let stream = StreamOptions { offset, length, hash: options.hash, no_trailer: options.no_trailer, compress: options.compress };
+            let streamer = match conn.open_streamer(full, stream) {
+                Ok(streamer) => streamer,
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                    conn.respond(b"ERR invalid range\n");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Prepare: header will be queued on writable, after any earlier responses
+            conn.queue_file(streamer);
+        }
+        "MGET" => {
+            // MGET <name|glob>... [HASH=<alg>]
+            // Replies `MGET <count>`, then <count> frames in order: `MFILE <name> <size>`,
+            // body, newline and hash trailer as for GET, or `ERR <name|glob> <reason>`.
+            let (args, options) = match split_options(&parts[1..], false) {
+                Ok(split) => split,
+                Err(e) => {
+                    conn.respond(format!("ERR {}\n", e).as_bytes());
+                    return Ok(());
+                }
+            };
+            if args.is_empty() {
+                conn.respond(b"ERR usage: MGET <name|glob>... [HASH=<alg>]\n");
+                return Ok(());
+            }
+            if !conn.negotiated(options.requires().chain([Capability::Mget])) {
+                return Ok(());
+            }
+            let mut frames = Vec::new();
+            for arg in args {
+                let pattern = unescape_name(arg);
+                match expand_pattern(mount_dir, &pattern, &conn.checksums.read().unwrap()) {
+                    Ok(matches) if matches.is_empty() => frames.push(Err(format!("ERR {} no match\n", arg))),
+                    Ok(matches) => frames.extend(matches.into_iter().map(Ok)),
+                    Err(e) => frames.push(Err(format!("ERR {} {}", arg, resolve_error(&e).trim_start_matches("ERR ")))),
+                }
+                if frames.len() > MAX_MGET_FILES {
+                    conn.respond(format!("ERR more than {} matches\n", MAX_MGET_FILES).as_bytes());
+                    return Ok(());
+                }
+            }
+            debug!(frames = frames.len(), "MGET expanded");
+            conn.respond(format!("MGET {}\n", frames.len()).as_bytes());
+            for frame in frames {
+                match frame {
+                    Ok((path, name)) => conn.queued.push_back(Outgoing::Named { path, name, hash: options.hash }),
+                    Err(line) => conn.respond(line.as_bytes()),
+                }
+            }
+        }
+        "GETDIR" => {
+            // GETDIR <dir> [tar|tar.zst] [HASH=<alg>]
+            let (args, options) = match split_options(&parts[1..], false) {
+                Ok(split) => split,
+                Err(e) => {
+                    conn.respond(format!("ERR {}\n", e).as_bytes());
+                    return Ok(());
+                }
+            };
+            let ([dir_arg] | [dir_arg, _]) = args[..] else {
+                conn.respond(b"ERR usage: GETDIR <dir> [tar|tar.zst] [HASH=<alg>]\n");
+                return Ok(());
+            };
+            let format = match args.get(1).map(|s| s.parse::<ArchiveFormat>()) {
+                None => ArchiveFormat::default(),
+                Some(Ok(format)) => format,
+                Some(Err(e)) => {
+                    conn.respond(format!("ERR {}\n", e).as_bytes());
+                    return Ok(());
+                }
+            };
+            let zstd = (format == ArchiveFormat::TarZst).then_some(Capability::Zstd);
+            if !conn.negotiated(options.requires().chain([Capability::Getdir]).chain(zstd)) {
+                return Ok(());
+            }
+            let dir = match resolve_path(mount_dir, &unescape_name(dir_arg)) {
+                Ok(dir) if dir.is_dir() => dir,
+                Ok(_) => {
+                    conn.respond(b"ERR not a directory\n");
+                    return Ok(());
+                }
+                Err(e) => {
+                    conn.respond(resolve_error(&e).as_bytes());
+                    return Ok(());
+                }
+            };
+
+            let mut entries = Vec::new();
+            let listed = list_dir(mount_dir, &dir, true, &conn.checksums.read().unwrap(), &mut entries);
+            if let Err(e) = listed {
+                warn!(dir = %dir.display(), error = %e, "cannot list directory");
+                conn.respond(resolve_error(&e).as_bytes());
+                return Ok(());
+            }
+            debug!(dir = %dir.display(), entries = entries.len(), %format, "GETDIR archive started");
+            conn.queue_archive(TarStreamer::new(mount_dir.to_path_buf(), entries, format, options.hash)?);
+        }
+        "PUT" => {
+            // PUT <name> <size> [HASH=<alg>]
+            let Some(size) = put_size(&parts) else {
+                conn.respond(b"ERR usage: PUT <name> <size> [HASH=<alg>]\n");
+                return Ok(());
+            };
+            // The client sends the body whatever we answer, so from here on
+            // a refusal has to skip it
+            match start_upload(conn, &parts[1..], size, mount_dir)? {
+                Some(upload) => conn.current_upload = Some(upload),
+                None => conn.discarding = Some(size),
+            }
+        }
+        "MD5" | "HASH" => {
+            // Trailer of a PUT, same framing as the FILE/MD5 response:
+            // `MD5 <hex>` or `HASH <alg> <hex>`
+            let upload = match conn.current_upload.take() {
+                Some(upload) if !upload.receiving_body() => upload,
+                other => {
+                    conn.current_upload = other;
+                    conn.respond(b"ERR no upload awaiting a hash\n");
+                    return Ok(());
+                }
+            };
+            let (algorithm, hex) = match parts[..] {
+                ["MD5", hex] => (Algorithm::Md5.name(), hex),
+                ["HASH", algorithm, hex] => (algorithm, hex),
+                _ => {
+                    conn.respond(b"ERR malformed hash trailer\n");
+                    return Ok(());
+                }
+            };
+            let expected = upload.checksum.algorithm();
+            if !expected.name().eq_ignore_ascii_case(algorithm) {
+                conn.respond(format!("ERR upload was hashed with {}\n", expected).as_bytes());
+                return Ok(());
+            }
+            let finished = upload.finish(hex, &mut conn.checksums.write().unwrap())?;
+            match finished {
+                Ok(()) => {
+                    info!(algorithm = %expected, digest = %hex, "upload complete");
+                    conn.respond(format!("OK {}\n", hex).as_bytes());
+                }
+                Err(ours) => {
+                    warn!(algorithm = %expected, client = %hex, server = %ours, "upload digest mismatch");
+                    conn.respond(format!("ERR {} mismatch {}\n", expected, ours).as_bytes());
+                }
+            }
+        }
+        _ => {
+            conn.respond(b"ERR unknown command
+");
+        }
+    }
+    Ok(())
+}
+
+/// The body size a `PUT` line announces, if it names one. A client sends
+/// that many bytes after the line even if the PUT is refused.
+fn put_size(parts: &[&str]) -> Option<u64> {
+    let ["PUT", args @ ..] = parts else {
+        return None;
+    };
+    args.iter().filter(|arg| !arg.contains('=')).nth(1)?.parse().ok()
+}
+
+/// Check a `PUT` and open its temp file. None if it was refused, with the
+/// reason already sent.
+fn start_upload(conn: &mut Connection, args: &[&str], size: u64, mount_dir: &Path) -> io::Result<Option<FileUpload>> {
+    let (args, options) = match split_options(args, false) {
+        Ok(split) => split,
+        Err(e) => {
+            conn.respond(format!("ERR {}\n", e).as_bytes());
+            return Ok(None);
+        }
+    };
+    if !conn.negotiated(options.requires().chain([Capability::Put])) {
+        return Ok(None);
+    }
+    if conn.current_upload.is_some() {
+        conn.respond(b"ERR upload already in progress\n");
+        return Ok(None);
+    }
+    let full = match resolve_new_path(mount_dir, &unescape_name(args[0])) {
+        Ok(full) => full,
+        Err(e) => {
+            conn.respond(resolve_error(&e).as_bytes());
+            return Ok(None);
+        }
+    };
+    let (Some(parent), Some(name)) = (full.parent(), full.file_name().and_then(|n| n.to_str())) else {
+        conn.respond(b"ERR invalid filename\n");
+        return Ok(None);
+    };
+    if is_hidden(name) || full.is_dir() {
+        conn.respond(b"ERR invalid filename\n");
+        return Ok(None);
+    }
+    // Tokens are unique within this server and the pid tells servers
+    // sharing a mount apart; create_new refuses anything left over
+    let tmp = parent.join(format!("{}{}-{}-{}", UPLOAD_TMP_PREFIX, std::process::id(), conn.token.0, name));
+    info!(path = %full.display(), size, "receiving upload");
+    FileUpload::new(tmp, full, size, options.hash.unwrap_or_default()).map(Some)
+}
+
+/// `KEY=value` options given with a command.
+#[derive(Debug, Default)]
+struct Options {
+    hash: Option<Algorithm>,
+    no_trailer: bool, // `HASH=none`
+    compress: Option<Compression>,
+}
+
+impl Options {
+    /// Capabilities these options rely on.
+    fn requires(&self) -> impl Iterator<Item = Capability> {
+        self.hash.and_then(Capability::for_hash).into_iter()
+            .chain(self.no_trailer.then_some(Capability::HashNone))
+            .chain(self.compress.map(Capability::for_compression))
+    }
+}
+
+/// Split command arguments into positional values and `KEY=value` options.
+/// `HASH=<alg>` is accepted everywhere, `COMPRESS=<codec>` and `HASH=none`
+/// only for `GET`.
+fn split_options<'a>(args: &[&'a str], get: bool) -> Result<(Vec<&'a str>, Options), String> {
+    let mut positional = Vec::new();
+    let mut options = Options::default();
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("HASH", "none")) if get => options.no_trailer = true,
+            Some(("HASH", value)) => options.hash = Some(value.parse::<Algorithm>()?),
+            Some(("COMPRESS", value)) if get => options.compress = Some(value.parse::<Compression>()?),
+            Some((key, _)) => return Err(format!("unknown option {}", key)),
+            None => positional.push(*arg),
+        }
+    }
+    Ok((positional, options))
+}
+
+/// Lexically normalize a client-supplied path into plain components.
+/// `.` is dropped and `..` pops a component; absolute paths and anything
+/// climbing above the root are rejected.
+fn normalize_relative(requested: &str) -> io::Result<PathBuf> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid path");
+    let mut out = PathBuf::new();
+    for component in Path::new(requested).components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    return Err(invalid());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(invalid()),
+        }
+    }
+    Ok(out)
+}
+
+/// Resolve an existing path under the (canonical) mount root. Symlinks are
+/// followed and the target must still lie inside the root.
+fn resolve_path(root: &Path, requested: &str) -> io::Result<PathBuf> {
+    let relative = normalize_relative(requested)?;
+    if relative.file_name().and_then(|name| name.to_str()).is_some_and(is_hidden) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "hidden file"));
+    }
+    let full = root.join(relative).canonicalize()?;
+    if !full.starts_with(root) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "path escapes mount"));
+    }
+    Ok(full)
+}
+
+/// Like `resolve_path`, for a file that may not exist yet (PUT targets).
+/// Only the parent directory has to exist.
+fn resolve_new_path(root: &Path, requested: &str) -> io::Result<PathBuf> {
+    let relative = normalize_relative(requested)?;
+    let name = relative.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid path"))?;
+    let parent = resolve_path(root, relative.parent().and_then(Path::to_str).unwrap_or("."))?;
+    if !parent.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "parent is not a directory"));
+    }
+    Ok(parent.join(name))
+}
+
+fn resolve_error(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::NotFound => "ERR file not found\n",
+        io::ErrorKind::PermissionDenied => "ERR access denied\n",
+        _ => "ERR invalid path\n",
+    }
+}
+
+/// Expand an MGET argument to (canonical path, listed name) pairs. Plain
+/// names are looked up directly; globs are matched against names relative
+/// to the root, where `*` stops at `/` and `**` crosses directories.
+fn expand_pattern(root: &Path, pattern: &str, checksums: &ChecksumIndex) -> io::Result<Vec<(PathBuf, String)>> {
+    const GLOB_CHARS: &[char] = &['*', '?', '[', '{'];
+    if !pattern.contains(GLOB_CHARS) {
+        let full = resolve_path(root, pattern)?;
+        if !full.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not a file"));
+        }
+        let name = normalize_relative(pattern)?.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+        return Ok(vec![(full, name)]);
+    }
+
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .compile_matcher();
+    // Only walk below the literal directory prefix, and only recurse if the
+    // rest of the pattern can reach into subdirectories
+    let (base, rest) = match pattern.find(GLOB_CHARS).and_then(|i| pattern[..i].rfind('/')) {
+        Some(slash) => (&pattern[..slash], &pattern[slash + 1..]),
+        None => (".", pattern),
+    };
+    let dir = resolve_path(root, base)?;
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    list_dir(root, &dir, rest.contains('/') || rest.contains("**"), checksums, &mut entries)?;
+
+    let mut matches = Vec::new();
+    for entry in entries {
+        if entry.kind == EntryKind::Dir || !matcher.is_match(&entry.name) {
+            continue;
+        }
+        // list_dir only yields files and symlinks that stay inside the
+        // root, but one may have gone since
+        match resolve_path(root, &entry.name) {
+            Ok(path) => matches.push((path, entry.name)),
+            Err(e) => warn!(entry = %entry.name, error = %e, "skipping MGET match"),
+        }
+    }
+    Ok(matches)
+}
+
+/// Collect entries under `dir` with paths relative to `root`, `/`-separated.
+/// Symlinks are listed only if they resolve to a file inside the root and
+/// are never descended into. Only failing to read `dir` itself is an
+/// error; entries removed meanwhile or unreadable below it are logged and
+/// left out.
+fn list_dir(root: &Path, dir: &Path, recursive: bool, checksums: &ChecksumIndex, out: &mut Vec<Entry>) -> io::Result<()> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!(dir = %dir.display(), error = %e, "skipping unreadable directory entry"),
+        }
+    }
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if entry.file_name().to_str().is_none_or(is_hidden) {
+            continue;
+        }
+        let path = entry.path();
+        let entry = match entry_for(root, &path, checksums) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "skipping entry");
+                continue;
+            }
+        };
+        let descend = recursive && entry.kind == EntryKind::Dir;
+        out.push(entry);
+        if descend && let Err(e) = list_dir(root, &path, recursive, checksums, out) {
+            warn!(dir = %path.display(), error = %e, "skipping unreadable directory");
+        }
+    }
+    Ok(())
+}
+
+/// The `LIST -l` entry for `path`, a location under `root` as the client
+/// named it. None for anything but files, directories and symlinks that
+/// resolve to a file inside the root. The root itself is named `.`.
+fn entry_for(root: &Path, path: &Path, checksums: &ChecksumIndex) -> io::Result<Option<Entry>> {
+    let file_type = std::fs::symlink_metadata(path)?.file_type();
+    let (kind, target) = if file_type.is_symlink() {
+        match path.canonicalize() {
+            Ok(target) if target.starts_with(root) && target.is_file() => (EntryKind::Symlink, target),
+            _ => return Ok(None),
+        }
+    } else if file_type.is_file() {
+        (EntryKind::File, path.to_path_buf())
+    } else if file_type.is_dir() {
+        (EntryKind::Dir, path.to_path_buf())
+    } else {
+        return Ok(None);
+    };
+    let Some(relative) = path.strip_prefix(root).ok().and_then(Path::to_str) else {
+        return Ok(None);
+    };
+
+    let metadata = std::fs::metadata(&target)?;
+    Ok(Some(Entry {
+        kind,
+        size: if kind == EntryKind::Dir { 0 } else { metadata.len() },
+        mtime: metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs()),
+        md5: checksums.get(&target, &metadata, Algorithm::Md5).map(str::to_string),
+        name: match relative {
+            "" => ".".to_string(),
+            relative => relative.replace(std::path::MAIN_SEPARATOR, "/"),
+        },
+    }))
+}
+
+/// Upload temp files and the checksum index: in the mount, but not files
+/// clients can see or touch.
+fn is_hidden(name: &str) -> bool {
+    name.starts_with(UPLOAD_TMP_PREFIX) || name.starts_with(INDEX_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A scratch directory holding `mount/` and `outside/`, removed on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("bfs-test-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("mount/sub")).unwrap();
+            std::fs::create_dir_all(dir.join("outside")).unwrap();
+            std::fs::write(dir.join("mount/file.txt"), b"inside").unwrap();
+            std::fs::write(dir.join("mount/sub/nested.txt"), b"nested").unwrap();
+            std::fs::write(dir.join("outside/secret.txt"), b"secret").unwrap();
+            Scratch(dir.canonicalize().unwrap())
+        }
+
+        fn root(&self) -> PathBuf {
+            self.0.join("mount")
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn normalize_drops_dots_and_rejects_escapes() {
+        assert_eq!(normalize_relative("a/./b/../c").unwrap(), PathBuf::from("a/c"));
+        assert_eq!(normalize_relative(".").unwrap(), PathBuf::new());
+        assert_eq!(normalize_relative("sub/..").unwrap(), PathBuf::new());
+        for bad in ["..", "../x", "a/../../x", "/etc/passwd", "/"] {
+            assert_eq!(normalize_relative(bad).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn resolve_stays_inside_the_mount() {
+        let scratch = Scratch::new("resolve");
+        let root = scratch.root();
+        assert_eq!(resolve_path(&root, "file.txt").unwrap(), root.join("file.txt"));
+        assert_eq!(resolve_path(&root, "sub/../sub/nested.txt").unwrap(), root.join("sub/nested.txt"));
+        assert_eq!(resolve_path(&root, ".").unwrap(), root);
+        assert_eq!(resolve_path(&root, "../outside/secret.txt").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(resolve_path(&root, &scratch.0.join("outside/secret.txt").to_string_lossy()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(resolve_path(&root, "missing.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn resolve_follows_symlinks_only_within_the_mount() {
+        let scratch = Scratch::new("symlinks");
+        let root = scratch.root();
+        symlink(root.join("file.txt"), root.join("inner-link")).unwrap();
+        symlink(scratch.0.join("outside/secret.txt"), root.join("outer-link")).unwrap();
+        symlink(scratch.0.join("outside"), root.join("outer-dir")).unwrap();
+
+        assert_eq!(resolve_path(&root, "inner-link").unwrap(), root.join("file.txt"));
+        assert_eq!(resolve_path(&root, "outer-link").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(resolve_path(&root, "outer-dir/secret.txt").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(resolve_new_path(&root, "outer-dir/new.txt").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn resolve_hides_temp_files_and_the_index() {
+        let scratch = Scratch::new("hidden");
+        let root = scratch.root();
+        std::fs::write(root.join(INDEX_FILE), b"").unwrap();
+        std::fs::write(root.join("sub/.upload-1-2-x"), b"").unwrap();
+        assert_eq!(resolve_path(&root, INDEX_FILE).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolve_path(&root, "sub/.upload-1-2-x").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(is_hidden(&format!("{}.tmp", INDEX_FILE)));
+        assert!(!is_hidden("visible.txt"));
+    }
+
+    #[test]
+    fn list_dir_leaves_out_hidden_and_escaping_entries() {
+        let scratch = Scratch::new("list");
+        let root = scratch.root();
+        std::fs::write(root.join(INDEX_FILE), b"").unwrap();
+        symlink(scratch.0.join("outside/secret.txt"), root.join("outer-link")).unwrap();
+        symlink(root.join("gone.txt"), root.join("dangling")).unwrap();
+        let mut entries = Vec::new();
+        list_dir(&root, &root, true, &ChecksumIndex::default(), &mut entries).unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["file.txt", "sub", "sub/nested.txt"]);
+        assert!(list_dir(&root, &root.join("missing"), true, &ChecksumIndex::default(), &mut entries).is_err());
+    }
+
+    #[test]
+    fn resolve_new_path_needs_an_existing_parent_inside() {
+        let scratch = Scratch::new("new-path");
+        let root = scratch.root();
+        assert_eq!(resolve_new_path(&root, "sub/new.txt").unwrap(), root.join("sub/new.txt"));
+        assert_eq!(resolve_new_path(&root, "new.txt").unwrap(), root.join("new.txt"));
+        assert_eq!(resolve_new_path(&root, "nope/new.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolve_new_path(&root, "file.txt/new.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolve_new_path(&root, "../outside/new.txt").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(resolve_new_path(&root, ".").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+use tracing::{debug, warn};
+
+use crate::client::{is_dropped_connection, Client, Session};
+use crate::protocol::{Entry, EntryKind};
+
+const HISTORY_FILE: &str = ".bfs_history";
+
+/// Every command with its usage and what it does, in the order `help` lists them.
+const COMMANDS: [(&str, &str, &str); 12] = [
+    ("ls", "ls [-l] [dir]", "list a remote directory"),
+    ("cd", "cd [dir]", "change the remote directory; no argument goes to the root"),
+    ("pwd", "pwd", "print the remote directory"),
+    ("get", "get <remote> [local]", "download a file into the local directory"),
+    ("put", "put <local> [remote]", "upload a file into the remote directory"),
+    ("stat", "stat <remote>", "show type, size, mtime and MD5 of a remote entry"),
+    ("lcd", "lcd [dir]", "change the local directory; no argument goes home"),
+    ("lpwd", "lpwd", "print the local directory"),
+    ("!", "!<command>", "run a command in the local directory"),
+    ("help", "help [command]", "show this list, or one command's usage"),
+    ("exit", "exit", "close the connection and quit"),
+    ("quit", "quit", "same as exit"),
+];
+
+/// Run the interactive shell over `session` until `exit` or end of input.
+/// Downloads land in the local directory, which starts out as `local_dir`
+/// when given. If the connection drops, a command is retried once on a
+/// fresh one.
+pub async fn run(client: &Client, session: Session, local_dir: Option<&Path>) -> io::Result<()> {
+    if let Some(dir) = local_dir {
+        std::fs::create_dir_all(dir)?;
+        std::env::set_current_dir(dir)?;
+    }
+    let config = Config::builder().completion_type(CompletionType::List).auto_add_history(false).build();
+    let mut editor = Editor::with_config(config).map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper { cwd: String::new(), listings: HashMap::new(), files: FilenameCompleter::new() }));
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history
+        && let Err(e) = editor.load_history(path)
+    {
+        debug!(error = %e, "no shell history loaded");
+    }
+
+    let mut shell = Shell { client, session, cwd: String::new(), editor };
+    // Seed tab completion with the root
+    if let Err(e) = shell.list("").await {
+        println!("error: {}", e);
+    }
+    println!("Type `help` for a list of commands.");
+    loop {
+        let prompt = format!("bfs:/{}> ", shell.cwd);
+        let line = match shell.editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C drops the line being typed, as in a shell
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = shell.editor.add_history_entry(line);
+
+        let mut result = shell.execute(line).await;
+        if let Err(e) = &result
+            && is_dropped_connection(e)
+        {
+            warn!(error = %e, "connection lost, reconnecting");
+            result = match client.connect().await {
+                Ok(session) => {
+                    shell.session = session;
+                    shell.execute(line).await
+                }
+                Err(e) => Err(e),
+            };
+        }
+        match result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    if let Some(path) = &history
+        && let Err(e) = shell.editor.save_history(path)
+    {
+        warn!(error = %e, path = %path.display(), "cannot save shell history");
+    }
+    Ok(())
+}
+
+struct Shell<'a> {
+    client: &'a Client,
+    session: Session,
+    cwd: String, // remote, relative to the mount; empty at the root
+    editor: Editor<ShellHelper, DefaultHistory>,
+}
+
+impl Shell<'_> {
+    /// Run one command line. Returns false when the shell should exit.
+    async fn execute(&mut self, line: &str) -> io::Result<bool> {
+        if let Some(command) = line.strip_prefix('!') {
+            run_local(command)?;
+            return Ok(true);
+        }
+        let args = split_args(line);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args[..] {
+            ["ls"] => self.print_listing("", false).await?,
+            ["ls", "-l"] => self.print_listing("", true).await?,
+            ["ls", dir] => self.print_listing(dir, false).await?,
+            ["ls", "-l", dir] => self.print_listing(dir, true).await?,
+            ["cd"] => self.change_dir(String::new()),
+            ["cd", dir] => {
+                let dir = resolve(&self.cwd, dir);
+                self.list(&dir).await?;
+                self.change_dir(dir);
+            }
+            ["pwd"] => println!("/{}", self.cwd),
+            ["get", remote] | ["get", remote, _] => {
+                let remote = resolve(&self.cwd, remote);
+                let name = remote.rsplit('/').next().unwrap_or(&remote);
+                let local = match args.get(2).map(PathBuf::from) {
+                    Some(local) if local.is_dir() => local.join(name),
+                    Some(local) => local,
+                    None => PathBuf::from(name),
+                };
+                self.client.get(&mut self.session, &remote, &local).await?;
+            }
+            ["put", local] | ["put", local, _] => {
+                let local = Path::new(local);
+                let name = local.file_name().and_then(|n| n.to_str())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid file name: {:?}", local)))?;
+                let remote = match args.get(2) {
+                    Some(dir) if dir.ends_with('/') => resolve(&self.cwd, &format!("{}{}", dir, name)),
+                    Some(remote) => resolve(&self.cwd, remote),
+                    None => resolve(&self.cwd, name),
+                };
+                self.client.put_as(&mut self.session, local, &remote).await?;
+                // Drop the stale listing; the next ls or cd of it fetches a fresh one
+                let dir = remote.rsplit_once('/').map_or("", |(dir, _)| dir);
+                self.helper().listings.remove(dir);
+            }
+            ["stat", remote] => {
+                let remote = resolve(&self.cwd, remote);
+                let Some(entry) = self.client.stat(&mut self.session, &remote).await? else {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("/{}: no such file or directory", remote)));
+                };
+                print_stat(&entry);
+            }
+            ["lcd"] => {
+                let home = std::env::var_os("HOME").ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+                std::env::set_current_dir(home)?;
+            }
+            ["lcd", dir] => std::env::set_current_dir(dir)?,
+            ["lpwd"] => println!("{}", std::env::current_dir()?.display()),
+            ["help"] => {
+                for (_, usage, description) in COMMANDS {
+                    println!("  {:<22}  {}", usage, description);
+                }
+            }
+            ["help", command] => match COMMANDS.iter().find(|(name, ..)| *name == command) {
+                Some((_, usage, description)) => println!("  {}  {}", usage, description),
+                None => println!("unknown command: {}", command),
+            },
+            ["exit" | "quit"] => return Ok(false),
+            [command, ..] if let Some((_, usage, _)) = COMMANDS.iter().find(|(name, ..)| *name == command) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("usage: {}", usage)));
+            }
+            [command, ..] => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown command: {} (try `help`)", command)));
+            }
+            [] => {}
+        }
+        Ok(true)
+    }
+
+    fn helper(&mut self) -> &mut ShellHelper {
+        self.editor.helper_mut().expect("shell helper is set")
+    }
+
+    fn change_dir(&mut self, dir: String) {
+        self.helper().cwd = dir.clone();
+        self.cwd = dir;
+    }
+
+    /// List `dir` and keep the result for tab completion.
+    async fn list(&mut self, dir: &str) -> io::Result<Vec<Entry>> {
+        let entries = self.client.list_dir(&mut self.session, dir).await?;
+        self.helper().listings.insert(dir.to_string(), entries.clone());
+        Ok(entries)
+    }
+
+    async fn print_listing(&mut self, dir: &str, long: bool) -> io::Result<()> {
+        let dir = resolve(&self.cwd, dir);
+        for entry in self.list(&dir).await? {
+            let name = display_name(&entry);
+            if long {
+                println!("{} {:>12} {:>11} {}", kind_char(entry.kind), entry.size, entry.mtime, name);
+            } else {
+                println!("{}", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn readline_error(e: ReadlineError) -> io::Error {
+    match e {
+        ReadlineError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Run `command` with `sh -c`, or an interactive `$SHELL` when it is empty.
+fn run_local(command: &str) -> io::Result<()> {
+    let status = if command.trim().is_empty() {
+        Command::new(std::env::var_os("SHELL").unwrap_or_else(|| "sh".into())).status()?
+    } else {
+        Command::new("sh").arg("-c").arg(command).status()?
+    };
+    if !status.success() {
+        println!("{}", status);
+    }
+    Ok(())
+}
+
+fn print_stat(entry: &Entry) {
+    let kind = match entry.kind {
+        EntryKind::File => "file",
+        EntryKind::Dir => "directory",
+        EntryKind::Symlink => "symlink",
+    };
+    println!("  name: /{}", entry.name);
+    println!("  type: {}", kind);
+    println!("  size: {}", entry.size);
+    println!(" mtime: {}", entry.mtime);
+    println!("   md5: {}", entry.md5.as_deref().unwrap_or("-"));
+}
+
+fn kind_char(kind: EntryKind) -> char {
+    match kind {
+        EntryKind::File => '-',
+        EntryKind::Dir => 'd',
+        EntryKind::Symlink => 'l',
+    }
+}
+
+/// The last path component, with a `/` after directories.
+fn display_name(entry: &Entry) -> String {
+    let name = entry.name.rsplit('/').next().unwrap_or(&entry.name);
+    match entry.kind {
+        EntryKind::Dir => format!("{}/", name),
+        _ => name.to_string(),
+    }
+}
+
+/// `path` taken from the remote directory `cwd`, as a path under the mount
+/// with no leading slash. `..` stops at the root.
+fn resolve(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = match path.starts_with('/') {
+        true => Vec::new(),
+        false => cwd.split('/').filter(|part| !part.is_empty()).collect(),
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Split a command line on whitespace. A backslash escapes the character
+/// after it and double quotes group words, so names with spaces work either
+/// way, as tab completion writes them.
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => current.get_or_insert_default().extend(chars.next()),
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_default();
+            }
+            c if c.is_whitespace() && !quoted => args.extend(current.take()),
+            c => current.get_or_insert_default().push(c),
+        }
+    }
+    args.extend(current);
+    args
+}
+
+/// Where the word being completed starts: after the last space that is not
+/// escaped with a backslash.
+fn word_start(line: &str) -> usize {
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ' ' => start = i + 1,
+            _ => {}
+        }
+    }
+    start
+}
+
+fn escape_arg(arg: &str) -> String {
+    let mut out = String::with_capacity(arg.len());
+    for c in arg.chars() {
+        if c.is_whitespace() || c == '\\' || c == '"' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Line editor support: completes command names, remote paths from the
+/// listings seen so far and local paths for `put`, `lcd` and `!`.
+struct ShellHelper {
+    cwd: String,
+    listings: HashMap<String, Vec<Entry>>, // by directory, from every ls and cd
+    files: FilenameCompleter,
+}
+
+impl ShellHelper {
+    fn complete_remote(&self, word: &str, dirs_only: bool) -> Vec<Pair> {
+        let typed = split_args(word).pop().unwrap_or_default();
+        let (dir_part, prefix) = match typed.rfind('/') {
+            Some(slash) => typed.split_at(slash + 1),
+            None => ("", typed.as_str()),
+        };
+        let Some(entries) = self.listings.get(&resolve(&self.cwd, dir_part)) else {
+            return Vec::new();
+        };
+        entries.iter()
+            .filter(|entry| !dirs_only || entry.kind == EntryKind::Dir)
+            .map(display_name)
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Pair { replacement: escape_arg(&format!("{}{}", dir_part, name)), display: name })
+            .collect()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        if before.starts_with('!') {
+            return self.files.complete(line, pos, ctx);
+        }
+        let start = word_start(before);
+        let word = &before[start..];
+        let command = before.split_whitespace().next().unwrap_or("");
+        let candidates = match command {
+            _ if start == 0 => COMMANDS.iter()
+                .filter(|(name, ..)| *name != "!" && name.starts_with(word))
+                .map(|(name, ..)| Pair { display: name.to_string(), replacement: format!("{} ", name) })
+                .collect(),
+            "put" | "lcd" => return self.files.complete(line, pos, ctx),
+            "cd" => self.complete_remote(word, true),
+            "ls" | "get" | "stat" => self.complete_remote(word, false),
+            _ => Vec::new(),
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}